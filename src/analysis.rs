@@ -0,0 +1,220 @@
+// Function discovery and cross references over the traced code
+//
+// Functions start at the image entry, the interrupt vectors, CALL targets and anywhere
+// a library signature from signatures.cfg matches. Each one covers the code reachable
+// from its start without following calls or running into another function.
+//
+// Xrefs record every call, branch, absolute operand and address-like immediate by the
+// address it refers to.
+
+use collections::{HashMap, HashSet};
+use conf;
+use cpu;
+use cpu::{Absolute, Const};
+
+static SIGNATURES : &'static str = include_str!("../signatures.cfg");
+
+pub struct Function {
+    start: u16,
+    end: u16,           //one past the last byte
+    name: ~str
+}
+
+pub struct Analysis {
+    functions: ~[Function],         //sorted by start
+    comments: HashMap<u16, ~str>,   //names of the functions called or branched to, by address
+    xrefs: HashMap<u16, ~[Xref]>    //references by target address
+}
+
+pub enum XrefKind {
+    CallRef,
+    JumpRef,            //conditional jumps, JMP and BR
+    ReadRef,            //&addr source operand
+    WriteRef,           //&addr destination operand
+    ImmRef              //#addr, e.g. a buffer or string passed to a function
+}
+
+pub struct Xref {
+    from: u16,
+    kind: XrefKind
+}
+
+impl XrefKind {
+    pub fn describe(&self) -> &'static str {
+        match *self {
+            CallRef => "call",
+            JumpRef => "jump",
+            ReadRef => "read",
+            WriteRef => "write",
+            ImmRef => "imm"
+        }
+    }
+}
+
+// immediates below this are taken to be plain numbers rather than addresses (the
+// peripheral and SFR space)
+static MIN_IMM_ADDR : u16 = 0x200;
+
+struct Signature {
+    name: ~str,
+    bytes: ~[Option<u8>]            //None matches any byte
+}
+
+impl Signature {
+    fn matches(&self, mem: &[u8], addr: u16) -> bool {
+        let addr = addr as uint;
+        if addr + self.bytes.len() > mem.len() { return false }
+        self.bytes.iter().enumerate().all(|(ix, b)| match *b {
+            Some(b) => mem[addr + ix] == b,
+            None => true
+        })
+    }
+}
+
+fn load_signatures() -> ~[Signature] {
+    let sections = match conf::parse(SIGNATURES) {
+        Ok(s) => s,
+        Err(e) => fail!(format!("signatures.cfg: {}", e))
+    };
+    let mut out = ~[];
+    for sec in sections.iter() {
+        let bytes = sec.get("bytes").unwrap_or(~"");
+        let bytes = bytes.words().map(|w| ::std::num::from_str_radix::<u8>(w, 16)).collect();
+        out.push(Signature { name: sec.name.clone(), bytes: bytes });
+    }
+    out
+}
+
+impl Analysis {
+    pub fn new() -> Analysis {
+        Analysis { functions: ~[], comments: HashMap::new(), xrefs: HashMap::new() }
+    }
+
+    // analyse a full 64KiB memory image
+    pub fn run(mem: &[u8], cpux: bool) -> Analysis {
+        let t = match cpu::trace(mem, cpux) {
+            Some(t) => t,
+            None => return Analysis::new()
+        };
+        let mut names = HashMap::new();
+        let mut starts = HashSet::new();
+        for e in t.entries.iter() {
+            if t.insts.contains_key(e) { starts.insert(*e); }
+        }
+        let sigs = load_signatures();
+        for (&addr, &(inst, len)) in t.insts.iter() {
+            if inst.is_call() {
+                for target in inst.flow(addr + len).move_iter() {
+                    if target != addr + len && t.insts.contains_key(&target) { starts.insert(target); }
+                }
+            }
+            for sig in sigs.iter() {
+                if sig.matches(mem, addr) {
+                    starts.insert(addr);
+                    names.insert(addr, sig.name.clone());
+                }
+            }
+        }
+
+        let mut functions = ~[];
+        for &start in starts.iter() {
+            let mut end = start;
+            let mut seen = HashSet::new();
+            let mut todo = ~[start];
+            while !todo.is_empty() {
+                let addr = todo.pop().unwrap();
+                if seen.contains(&addr) || (addr != start && starts.contains(&addr)) { continue }
+                seen.insert(addr);
+                let (inst, len) = match t.insts.find(&addr) {
+                    Some(&i) => i,
+                    None => continue
+                };
+                if addr + len > end { end = addr + len }
+                if inst.is_call() {
+                    todo.push(addr + len)
+                } else {
+                    todo.push_all(inst.flow(addr + len))
+                }
+            }
+            let name = names.find(&start).map(|n| n.clone()).unwrap_or(format!("sub_{:04x}", start));
+            functions.push(Function { start: start, end: end, name: name });
+        }
+        functions.sort_by(|a, b| a.start.cmp(&b.start));
+
+        let mut a = Analysis { functions: functions, comments: HashMap::new(), xrefs: HashMap::new() };
+        for (&addr, &(inst, len)) in t.insts.iter() {
+            let targets : ~[u16] = inst.flow(addr + len).move_iter().filter(|&t| t != addr + len).collect();
+            for &target in targets.iter() {
+                a.add_xref(target, addr, if inst.is_call() { CallRef } else { JumpRef });
+                if starts.contains(&target) {
+                    let label = a.label(target);
+                    a.comments.insert(addr, label);
+                }
+            }
+            for &(mode, reg, written) in inst.operands().iter() {
+                match mode {
+                    Absolute(target) => a.add_xref(target, addr, if written { WriteRef } else { ReadRef }),
+                    Const(n) if reg == 0 && n >= MIN_IMM_ADDR && !targets.contains(&n) => a.add_xref(n, addr, ImmRef),
+                    _ => ()
+                }
+            }
+        }
+        for (_, refs) in a.xrefs.mut_iter() {
+            refs.sort_by(|x, y| x.from.cmp(&y.from));
+        }
+        a
+    }
+
+    fn add_xref(&mut self, target: u16, from: u16, kind: XrefKind) {
+        self.xrefs.find_or_insert(target, ~[]).push(Xref { from: from, kind: kind });
+    }
+
+    pub fn xrefs_to<'a>(&'a self, addr: u16) -> &'a [Xref] {
+        match self.xrefs.find(&addr) {
+            Some(v) => v.as_slice(),
+            None => &[]
+        }
+    }
+
+    // one line per reference, for the xrefs command
+    pub fn describe_xrefs(&self, addr: u16) -> ~str {
+        let refs = self.xrefs_to(addr);
+        if refs.len() == 0 { return format!("No xrefs to {:04x}\n", addr) }
+        let mut out = format!("Xrefs to {:04x} {}\n", addr, self.label(addr));
+        for x in refs.iter() {
+            out.push_str(format!("  {:04x} {:5s} {}\n", x.from, x.kind.describe(), self.label(x.from)));
+        }
+        out
+    }
+
+    // short inline form for the disassembly pane
+    pub fn xref_note(&self, addr: u16) -> Option<~str> {
+        let refs = self.xrefs_to(addr);
+        if refs.len() == 0 { return None }
+        let froms : ~[~str] = refs.iter().take(2).map(|x| format!("{:04x}", x.from)).collect();
+        let more = if refs.len() > 2 { format!(" +{}", refs.len() - 2) } else { ~"" };
+        Some(format!("; xref {}{}", froms.connect(","), more))
+    }
+
+    // the innermost function covering addr
+    pub fn function<'a>(&'a self, addr: u16) -> Option<&'a Function> {
+        let mut found = None;
+        for f in self.functions.iter() {
+            if f.start <= addr && addr < f.end { found = Some(f) }
+        }
+        found
+    }
+
+    pub fn starting_at<'a>(&'a self, addr: u16) -> Option<&'a Function> {
+        self.functions.iter().find(|f| f.start == addr)
+    }
+
+    // <name+0x12>, or an empty string outside any known function
+    pub fn label(&self, addr: u16) -> ~str {
+        match self.function(addr) {
+            Some(f) if f.start == addr => format!("<{}>", f.name),
+            Some(f) => format!("<{}+0x{:x}>", f.name, addr - f.start),
+            None => ~""
+        }
+    }
+}
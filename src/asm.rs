@@ -0,0 +1,524 @@
+// MSP430 assembler
+//
+// Core and emulated mnemonics, all addressing modes, labels and the .word, .byte,
+// .ascii and .asciz directives. Statements are separated by newlines or ';' and
+// comments start with "//". Opcodes come from the decoder's tables in cpu.rs.
+
+use collections::HashMap;
+use cpu::{JUMP_NAMES, ONEARG_NAMES, TWOARG_NAMES};
+use std::ascii::StrAsciiExt;
+use std::io::File;
+use std::os;
+
+// a number, or a label ($ is the statement address) plus an offset
+#[deriving(Clone)]
+enum Value {
+    Lit(int),
+    Label(~str, int)
+}
+
+#[deriving(Clone)]
+enum Operand {
+    RegOp(u8),              //rN
+    IdxOp(Value, u8),       //x(rN)
+    SymOp(Value),           //x, pc relative
+    AbsOp(Value),           //&x
+    IndOp(u8),              //@rN
+    IncOp(u8),              //@rN+
+    ImmOp(Value)            //#x
+}
+
+enum Body {
+    Empty,
+    Inst(~str, bool, ~[Operand]),       //mnemonic, byte flag, operands
+    Words(~[Value]),
+    Bytes(~[Value]),
+    Ascii(~[u8])
+}
+
+struct Stmt {
+    text: ~str,
+    label: Option<~str>,
+    body: Body
+}
+
+struct Asm {
+    labels: HashMap<~str, u16>,
+    resolving: bool             //false on the first pass, when labels may be unknown
+}
+
+pub fn assemble(src: &str, org: u16) -> Result<~[u8], ~str> {
+    let mut out = ~[];
+    for (_, bytes, _) in try!(assemble_lines(src, org)).move_iter() {
+        out.push_all(bytes);
+    }
+    Ok(out)
+}
+
+// (address, bytes, source) for every statement
+pub fn assemble_lines(src: &str, org: u16) -> Result<~[(u16, ~[u8], ~str)], ~str> {
+    let mut stmts = ~[];
+    let lines : ~[&str] = src.lines().map(|l| strip_comment(l)).collect();
+    for text in split_top(lines.connect(";").as_slice(), ';').move_iter() {
+        let text = text.trim().to_owned();
+        stmts.push(try!(parse_stmt(text).map_err(|e| format!("{}: {}", text, e))));
+    }
+
+    let mut asm = Asm { labels: HashMap::new(), resolving: false };
+    let mut addr = org;
+    for st in stmts.iter() {
+        match st.label {
+            Some(ref l) => {
+                if asm.labels.contains_key(l) { return Err(format!("duplicate label {}", *l)) }
+                asm.labels.insert(l.clone(), addr);
+            },
+            None => ()
+        }
+        addr += try!(asm.encode(st, addr).map_err(|e| format!("{}: {}", st.text, e))).len() as u16;
+    }
+
+    asm.resolving = true;
+    let mut out = ~[];
+    addr = org;
+    for st in stmts.iter() {
+        let bytes = try!(asm.encode(st, addr).map_err(|e| format!("{}: {}", st.text, e)));
+        let len = bytes.len() as u16;
+        if len > 0 { out.push((addr, bytes, st.text.clone())) }
+        addr += len;
+    }
+    Ok(out)
+}
+
+// uctf asm: SOURCE is either a file or the assembly itself
+pub fn run(source: &str, org: u16) {
+    let path = Path::new(source);
+    let text = if path.is_file() {
+        match File::open(&path).read_to_str() {
+            Ok(t) => t,
+            Err(e) => { println!("Could not read {}: {}", source, e); os::set_exit_status(1); return }
+        }
+    } else {
+        source.to_owned()
+    };
+    match assemble_lines(text, org) {
+        Ok(lines) => {
+            let mut payload = ~"x";
+            for &(addr, ref bytes, ref src) in lines.iter() {
+                let hex : ~[~str] = bytes.iter().map(|b| format!("{:02x}", *b)).collect();
+                println!("{:04x}: {:24s} {}", addr, hex.concat(), *src);
+                payload.push_str(hex.concat());
+            }
+            println!("{}", payload);
+        },
+        Err(e) => { println!("{}", e); os::set_exit_status(1) }
+    }
+}
+
+impl Asm {
+    fn resolve(&self, v: &Value, here: u16) -> Result<int, ~str> {
+        match *v {
+            Lit(n) => Ok(n),
+            Label(ref l, off) if l.as_slice() == "$" => Ok(here as int + off),
+            Label(ref l, off) => match self.labels.find(l) {
+                Some(&a) => Ok(a as int + off),
+                None if !self.resolving => Ok(0),
+                None => Err(format!("undefined label {}", *l))
+            }
+        }
+    }
+
+    fn word(&self, v: &Value, here: u16) -> Result<u16, ~str> {
+        let n = try!(self.resolve(v, here));
+        if n < -0x8000 || n > 0xffff { return Err(format!("{} does not fit in a word", n)) }
+        Ok((n & 0xffff) as u16)
+    }
+
+    // register, mode bits and extension word of an operand of the statement at here,
+    // whose extension word would be stored at extaddr
+    fn operand(&self, op: &Operand, here: u16, extaddr: u16, dest: bool) -> Result<(u8, u16, Option<u16>), ~str> {
+        Ok(match *op {
+            RegOp(r) => (r, 0, None),
+            IdxOp(ref v, r) => (r, 1, Some(try!(self.word(v, here)))),
+            SymOp(ref v) => (0, 1, Some(try!(self.word(v, here)) - extaddr)),
+            AbsOp(ref v) => (2, 1, Some(try!(self.word(v, here)))),
+            IndOp(r) if dest => (r, 1, Some(0)),
+            IndOp(r) => (r, 2, None),
+            IncOp(_) if dest => return Err(~"@rN+ is not a valid destination"),
+            IncOp(r) => (r, 3, None),
+            ImmOp(_) if dest => return Err(~"immediate is not a valid destination"),
+            ImmOp(ref v) => match constgen(v) {
+                Some((r, mode)) => (r, mode, None),
+                None => (0, 3, Some(try!(self.word(v, here))))
+            }
+        })
+    }
+
+    fn encode(&self, st: &Stmt, addr: u16) -> Result<~[u8], ~str> {
+        let mut words : ~[u16] = ~[];
+        match st.body {
+            Empty => return Ok(~[]),
+            Ascii(ref bytes) => return Ok(bytes.clone()),
+            Bytes(ref vals) => {
+                let mut out = ~[];
+                for v in vals.iter() {
+                    let n = try!(self.resolve(v, addr));
+                    if n < -0x80 || n > 0xff { return Err(format!("{} does not fit in a byte", n)) }
+                    out.push((n & 0xff) as u8);
+                }
+                return Ok(out)
+            },
+            Words(ref vals) => {
+                if addr % 2 != 0 { return Err(~".word at odd address") }
+                for v in vals.iter() { words.push(try!(self.word(v, addr))) }
+            },
+            Inst(ref mnem, bw, ref ops) => {
+                if addr % 2 != 0 { return Err(~"instruction at odd address") }
+                let (mnem, ops) = try!(expand(mnem.as_slice(), ops));
+                let bwbit = if bw { 1u16 << 6 } else { 0 };
+                match jump_index(mnem) {
+                    Some(cond) => {
+                        if ops.len() != 1 { return Err(~"jump takes one operand") }
+                        let target = match ops[0] {
+                            SymOp(ref v) | AbsOp(ref v) | ImmOp(ref v) => try!(self.resolve(v, addr)),
+                            _ => return Err(~"jump target must be an address")
+                        };
+                        let off = target - (addr as int + 2);
+                        if self.resolving && (off % 2 != 0 || off < -1024 || off > 1022) {
+                            return Err(format!("jump target 0x{:04x} out of range", target))
+                        }
+                        words.push(0x2000 | (cond as u16 << 10) | ((off / 2) as u16 & 0x3ff));
+                    },
+                    None => match (index_of(ONEARG_NAMES.as_slice(), mnem), index_of(TWOARG_NAMES.as_slice(), mnem)) {
+                        (Some(6), _) => {                                           //RETI
+                            if ops.len() != 0 { return Err(~"RETI takes no operands") }
+                            words.push(0x1300);
+                        },
+                        (Some(op), _) => {
+                            if ops.len() != 1 { return Err(format!("{} takes one operand", mnem)) }
+                            let (reg, mode, ext) = try!(self.operand(&ops[0], addr, addr + 2, false));
+                            words.push(0x1000 | (op as u16 << 7) | bwbit | (mode << 4) | reg as u16);
+                            match ext { Some(e) => words.push(e), None => () }
+                        },
+                        (_, Some(op)) if op >= 4 => {
+                            if ops.len() != 2 { return Err(format!("{} takes two operands", mnem)) }
+                            let (sreg, smode, sext) = try!(self.operand(&ops[0], addr, addr + 2, false));
+                            let dextaddr = if sext.is_some() { addr + 4 } else { addr + 2 };
+                            let (dreg, dmode, dext) = try!(self.operand(&ops[1], addr, dextaddr, true));
+                            words.push((op as u16 << 12) | (sreg as u16 << 8) | (dmode << 7) | bwbit
+                                       | (smode << 4) | dreg as u16);
+                            match sext { Some(e) => words.push(e), None => () }
+                            match dext { Some(e) => words.push(e), None => () }
+                        },
+                        _ => return Err(format!("unknown mnemonic {}", mnem))
+                    }
+                }
+            }
+        }
+        let mut out = ~[];
+        for w in words.iter() {
+            out.push((*w & 0xff) as u8);
+            out.push((*w >> 8) as u8);
+        }
+        Ok(out)
+    }
+}
+
+fn index_of(table: &[&'static str], name: &str) -> Option<uint> {
+    table.iter().position(|&n| n == name)
+}
+
+fn jump_index(mnem: &str) -> Option<uint> {
+    let name = match mnem {
+        "JNZ" => "JNE",
+        "JZ" => "JEQ",
+        "JLO" => "JNC",
+        "JHS" => "JC",
+        m => m
+    };
+    index_of(JUMP_NAMES.as_slice(), name)
+}
+
+// immediates the constant generators r2/r3 can produce without an extension word
+fn constgen(v: &Value) -> Option<(u8, u16)> {
+    match *v {
+        Lit(0) => Some((3, 0)),
+        Lit(1) => Some((3, 1)),
+        Lit(2) => Some((3, 2)),
+        Lit(-1) | Lit(0xffff) => Some((3, 3)),
+        Lit(4) => Some((2, 2)),
+        Lit(8) => Some((2, 3)),
+        _ => None
+    }
+}
+
+// emulated instructions in terms of the core ones
+fn expand(mnem: &str, ops: &~[Operand]) -> Result<(~str, ~[Operand]), ~str> {
+    let imm = |n: int| ImmOp(Lit(n));
+    let sr = RegOp(2);
+    let (core, args) = match (mnem, ops.len()) {
+        ("NOP", 0) => ("MOV", ~[imm(0), RegOp(3)]),
+        ("RET", 0) => ("MOV", ~[IncOp(1), RegOp(0)]),
+        ("CLRC", 0) => ("BIC", ~[imm(1), sr]),
+        ("CLRZ", 0) => ("BIC", ~[imm(2), sr]),
+        ("CLRN", 0) => ("BIC", ~[imm(4), sr]),
+        ("DINT", 0) => ("BIC", ~[imm(8), sr]),
+        ("SETC", 0) => ("BIS", ~[imm(1), sr]),
+        ("SETZ", 0) => ("BIS", ~[imm(2), sr]),
+        ("SETN", 0) => ("BIS", ~[imm(4), sr]),
+        ("EINT", 0) => ("BIS", ~[imm(8), sr]),
+        ("POP", 1) => ("MOV", ~[IncOp(1), ops[0].clone()]),
+        ("BR", 1) => ("MOV", ~[ops[0].clone(), RegOp(0)]),
+        ("CLR", 1) => ("MOV", ~[imm(0), ops[0].clone()]),
+        ("INC", 1) => ("ADD", ~[imm(1), ops[0].clone()]),
+        ("INCD", 1) => ("ADD", ~[imm(2), ops[0].clone()]),
+        ("DEC", 1) => ("SUB", ~[imm(1), ops[0].clone()]),
+        ("DECD", 1) => ("SUB", ~[imm(2), ops[0].clone()]),
+        ("TST", 1) => ("CMP", ~[imm(0), ops[0].clone()]),
+        ("INV", 1) => ("XOR", ~[imm(-1), ops[0].clone()]),
+        ("ADC", 1) => ("ADDC", ~[imm(0), ops[0].clone()]),
+        ("SBC", 1) => ("SUBC", ~[imm(0), ops[0].clone()]),
+        ("DADC", 1) => ("DADD", ~[imm(0), ops[0].clone()]),
+        ("RLA", 1) => ("ADD", ~[ops[0].clone(), ops[0].clone()]),
+        ("RLC", 1) => ("ADDC", ~[ops[0].clone(), ops[0].clone()]),
+        (m, _) => return Ok((m.to_owned(), ops.clone()))
+    };
+    Ok((core.to_owned(), args))
+}
+
+// parsing
+
+// a line without its // comment; a // inside quotes is kept
+fn strip_comment<'a>(line: &'a str) -> &'a str {
+    let mut quote : Option<char> = None;
+    let mut escaped = false;
+    let mut prev = ' ';
+    for (ix, c) in line.char_indices() {
+        match quote {
+            Some(q) => {
+                if !escaped && c == q { quote = None }
+                escaped = !escaped && c == '\\';
+            },
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == '/' && prev == '/' => return line.slice_to(ix - 1),
+            None => ()
+        }
+        prev = c;
+    }
+    line
+}
+
+// split on sep, ignoring separators inside quotes and parentheses
+pub fn split_top(s: &str, sep: char) -> ~[~str] {
+    let mut out = ~[];
+    let mut cur = ~"";
+    let mut quote : Option<char> = None;
+    let mut depth = 0;
+    let mut escaped = false;
+    for c in s.chars() {
+        match quote {
+            Some(q) => {
+                if !escaped && c == q { quote = None }
+                escaped = !escaped && c == '\\';
+            },
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == '(' => depth += 1,
+            None if c == ')' => depth -= 1,
+            None if c == sep && depth == 0 => { out.push(cur); cur = ~""; continue },
+            None => ()
+        }
+        cur.push_char(c);
+    }
+    out.push(cur);
+    out
+}
+
+fn is_ident(s: &str) -> bool {
+    s.len() > 0 && !s.char_at(0).is_digit()
+        && s.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '.')
+}
+
+fn parse_stmt(text: &str) -> Result<Stmt, ~str> {
+    let mut rest = text;
+    let mut label = None;
+    match rest.find(':') {
+        Some(ix) if is_ident(rest.slice_to(ix).trim()) => {
+            label = Some(rest.slice_to(ix).trim().to_owned());
+            rest = rest.slice_from(ix + 1).trim();
+        },
+        _ => ()
+    }
+    let (mnem, args) = match rest.find(|c: char| c.is_whitespace()) {
+        Some(ix) => (rest.slice_to(ix), rest.slice_from(ix).trim()),
+        None => (rest, "")
+    };
+    let mnem = mnem.to_ascii_upper();
+    let body = match mnem.as_slice() {
+        "" => Empty,
+        ".WORD" => Words(try!(parse_values(args))),
+        ".BYTE" => Bytes(try!(parse_values(args))),
+        ".ASCII" => Ascii(try!(parse_string(args))),
+        ".ASCIZ" => { let mut s = try!(parse_string(args)); s.push(0); Ascii(s) },
+        _ => {
+            let (name, bw) = if mnem.ends_with(".B") {
+                (mnem.slice_to(mnem.len() - 2).to_owned(), true)
+            } else if mnem.ends_with(".W") {
+                (mnem.slice_to(mnem.len() - 2).to_owned(), false)
+            } else {
+                (mnem.clone(), false)
+            };
+            let mut ops = ~[];
+            if args.len() > 0 {
+                for a in split_top(args, ',').iter() { ops.push(try!(parse_operand(a.trim()))) }
+            }
+            Inst(name, bw, ops)
+        }
+    };
+    Ok(Stmt { text: text.to_owned(), label: label, body: body })
+}
+
+fn parse_values(args: &str) -> Result<~[Value], ~str> {
+    let mut out = ~[];
+    for a in split_top(args, ',').iter() { out.push(try!(parse_value(a.as_slice()))) }
+    Ok(out)
+}
+
+pub fn parse_string(args: &str) -> Result<~[u8], ~str> {
+    let s = args.trim();
+    if s.len() < 2 || !s.starts_with("\"") || !s.ends_with("\"") {
+        return Err(~"expected a quoted string")
+    }
+    let inner : ~[char] = s.slice(1, s.len() - 1).chars().collect();
+    let mut out = ~[];
+    let mut i = 0;
+    while i < inner.len() {
+        if inner[i] != '\\' || i + 1 == inner.len() {
+            out.push(inner[i] as u8);
+            i += 1;
+            continue
+        }
+        match inner[i + 1] {
+            'n' => out.push(10),
+            'r' => out.push(13),
+            't' => out.push(9),
+            '0' => out.push(0),
+            'x' if i + 3 < inner.len() => {
+                let hex : ~str = inner.slice(i + 2, i + 4).iter().map(|c| *c).collect();
+                match ::std::num::from_str_radix::<u8>(hex, 16) {
+                    Some(b) => out.push(b),
+                    None => return Err(format!("bad escape \\x{}", hex))
+                }
+                i += 2;
+            },
+            c => out.push(c as u8)
+        }
+        i += 2;
+    }
+    Ok(out)
+}
+
+pub fn parse_reg(s: &str) -> Option<u8> {
+    let s = s.trim().to_ascii_lower();
+    match s.as_slice() {
+        "pc" => Some(0),
+        "sp" => Some(1),
+        "sr" => Some(2),
+        "cg" => Some(3),
+        r if r.starts_with("r") => match from_str::<u8>(r.slice_from(1)) {
+            Some(n) if n < 16 => Some(n),
+            _ => None
+        },
+        _ => None
+    }
+}
+
+fn parse_operand(s: &str) -> Result<Operand, ~str> {
+    if s.len() == 0 { return Err(~"missing operand") }
+    if s.starts_with("#") { return Ok(ImmOp(try!(parse_value(s.slice_from(1))))) }
+    if s.starts_with("&") { return Ok(AbsOp(try!(parse_value(s.slice_from(1))))) }
+    if s.starts_with("@") {
+        let r = s.slice_from(1).trim();
+        let (name, inc) = if r.ends_with("+") { (r.slice_to(r.len() - 1), true) } else { (r, false) };
+        return match parse_reg(name) {
+            Some(n) if inc => Ok(IncOp(n)),
+            Some(n) => Ok(IndOp(n)),
+            None => Err(format!("bad register {}", name))
+        }
+    }
+    match parse_reg(s) {
+        Some(n) => return Ok(RegOp(n)),
+        None => ()
+    }
+    if s.ends_with(")") {
+        match s.rfind('(') {
+            Some(ix) => {
+                let reg = match parse_reg(s.slice(ix + 1, s.len() - 1)) {
+                    Some(n) => n,
+                    None => return Err(format!("bad register in {}", s))
+                };
+                let off = if ix == 0 { Lit(0) } else { try!(parse_value(s.slice_to(ix))) };
+                return Ok(IdxOp(off, reg))
+            },
+            None => ()
+        }
+    }
+    Ok(SymOp(try!(parse_value(s))))
+}
+
+// a value that needs no labels
+pub fn parse_number(s: &str) -> Result<int, ~str> {
+    match try!(parse_value(s)) {
+        Lit(n) => Ok(n),
+        Label(_, _) => Err(format!("bad value '{}'", s.trim()))
+    }
+}
+
+// sums and differences of numbers, characters, $ and at most one label
+fn parse_value(s: &str) -> Result<Value, ~str> {
+    let s = s.trim();
+    let mut terms : ~[(int, ~str)] = ~[];
+    let mut cur = ~"";
+    let mut sign = 1;
+    let mut quoted = false;
+    for c in s.chars() {
+        if c == '\'' { quoted = !quoted }
+        if !quoted && (c == '+' || c == '-') {
+            if cur.trim().len() > 0 {
+                terms.push((sign, cur.trim().to_owned()));
+                cur = ~"";
+                sign = 1;
+            }
+            if c == '-' { sign = -sign }
+            continue
+        }
+        cur.push_char(c);
+    }
+    if cur.trim().len() == 0 { return Err(format!("bad value '{}'", s)) }
+    terms.push((sign, cur.trim().to_owned()));
+
+    let mut total = 0i;
+    let mut label : Option<~str> = None;
+    for &(sign, ref t) in terms.iter() {
+        match parse_term(t.as_slice()) {
+            Some(n) => total += sign * n,
+            None if t.as_slice() == "$" || is_ident(t.as_slice()) => {
+                if label.is_some() || sign < 0 { return Err(format!("bad value '{}'", s)) }
+                label = Some(t.clone());
+            },
+            None => return Err(format!("bad value '{}'", s))
+        }
+    }
+    Ok(match label {
+        Some(l) => Label(l, total),
+        None => Lit(total)
+    })
+}
+
+fn parse_term(t: &str) -> Option<int> {
+    if t.starts_with("0x") || t.starts_with("0X") {
+        ::std::num::from_str_radix::<int>(t.slice_from(2), 16)
+    } else if t.len() == 3 && t.starts_with("'") && t.ends_with("'") {
+        Some(t.char_at(1) as int)
+    } else {
+        from_str::<int>(t)
+    }
+}
@@ -0,0 +1,148 @@
+// Control-flow graph of a single function, written as Graphviz DOT
+//
+// Blocks end at jumps, calls and returns, and wherever another block starts. Edges
+// are labelled with the jump taken (JEQ, JNE, ...) or fall-through; branches out of
+// the function lead to dashed nodes named after their target.
+
+use analysis::Analysis;
+use collections::HashSet;
+use cpu;
+use cpu::{Cpu, Instruction};
+use device::Device;
+use std::io::File;
+use std::os;
+
+pub struct Block {
+    start: u16,
+    insts: ~[(u16, Instruction)],
+    edges: ~[(u16, ~str)]           //successor and label
+}
+
+pub fn blocks(mem: &[u8], cpux: bool, symbols: &Analysis, func: u16) -> Result<~[Block], ~str> {
+    let t = match cpu::trace(mem, cpux) {
+        Some(t) => t,
+        None => return Err(~"no code in image")
+    };
+    if !t.insts.contains_key(&func) { return Err(format!("no code reached at {:04x}", func)) }
+
+    //the function body: everything reachable without calling or entering another function
+    let mut body = HashSet::new();
+    let mut leaders = HashSet::new();
+    leaders.insert(func);
+    let mut todo = ~[func];
+    while !todo.is_empty() {
+        let addr = todo.pop().unwrap();
+        if body.contains(&addr) || (addr != func && symbols.starting_at(addr).is_some()) { continue }
+        let (inst, len) = match t.insts.find(&addr) {
+            Some(&i) => i,
+            None => continue
+        };
+        body.insert(addr);
+        let next = addr + len;
+        let succ = if inst.is_call() { ~[next] } else { inst.flow(next) };
+        if inst.is_jump() || inst.is_call() {
+            for &s in succ.iter() { leaders.insert(s); }
+        }
+        todo.push_all(succ);
+    }
+
+    let mut starts : ~[u16] = leaders.iter().map(|&a| a).filter(|a| body.contains(a)).collect();
+    starts.sort();
+    let mut out = ~[];
+    for &start in starts.iter() {
+        let mut b = Block { start: start, insts: ~[], edges: ~[] };
+        let mut addr = start;
+        loop {
+            let (inst, len) = *t.insts.get(&addr);
+            b.insts.push((addr, inst));
+            let next = addr + len;
+            let succ = inst.flow(next);
+            if inst.is_jump() {
+                for (ix, &s) in succ.iter().enumerate() {
+                    b.edges.push((s, if ix == 0 { inst.namer() } else { ~"fall-through" }));
+                }
+                break
+            }
+            if !inst.is_call() && succ != ~[next] {
+                //RET, RETI and BR
+                for &s in succ.iter() { b.edges.push((s, ~"BR")) }
+                break
+            }
+            if inst.is_call() || leaders.contains(&next) || !body.contains(&next) {
+                b.edges.push((next, ~"fall-through"));
+                break
+            }
+            addr = next;
+        }
+        out.push(b);
+    }
+    Ok(out)
+}
+
+fn escape(s: &str) -> ~str {
+    s.replace("\\", "\\\\").replace("\"", "\\\"")
+}
+
+pub fn to_dot(name: &str, blocks: &[Block], symbols: &Analysis) -> ~str {
+    let mut out = format!("digraph \"{}\" ", escape(name));
+    out.push_str("{\n    node [shape=box, fontname=\"monospace\"];\n");
+    let starts : HashSet<u16> = blocks.iter().map(|b| b.start).collect();
+    let mut external = HashSet::new();
+    for b in blocks.iter() {
+        let mut label = format!("{}\\l", escape(symbols.label(b.start)));
+        for &(addr, inst) in b.insts.iter() {
+            let comment = symbols.comments.find(&addr).map(|c| format!(" {}", *c)).unwrap_or(~"");
+            label.push_str(format!("{:04x}: {}{}\\l", addr, escape(inst.to_string()), escape(comment)));
+        }
+        out.push_str(format!("    b{:04x} [label=\"{}\"];\n", b.start, label));
+        for &(target, ref cond) in b.edges.iter() {
+            let node = if starts.contains(&target) {
+                format!("b{:04x}", target)
+            } else {
+                external.insert(target);
+                format!("x{:04x}", target)
+            };
+            out.push_str(format!("    b{:04x} -> {} [label=\"{}\"];\n", b.start, node, *cond));
+        }
+    }
+    for &target in external.iter() {
+        let label = match symbols.label(target) {
+            ref l if l.len() > 0 => l.clone(),
+            _ => format!("{:04x}", target)
+        };
+        out.push_str(format!("    x{:04x} [label=\"{}\", style=dashed];\n", target, escape(label)));
+    }
+    out.push_str("}\n");
+    out
+}
+
+// uctf cfg --function ADDR|NAME IMAGE
+pub fn run(image: &[u8], dev: &Device, func: Option<~str>, path: Option<~str>) {
+    let cpu = Cpu::init(image, dev);
+    let mem = cpu.ram.bytes();
+    let symbols = Analysis::run(mem, dev.cpux);
+    let func = match func {
+        Some(f) => f,
+        None => { println!("cfg needs --function ADDR"); os::set_exit_status(1); return }
+    };
+    let addr = match symbols.functions.iter().find(|f| f.name == func) {
+        Some(f) => f.start,
+        None => match ::std::num::from_str_radix::<u16>(func, 16) {
+            Some(a) => a,
+            None => { println!("Unknown function {}", func); os::set_exit_status(1); return }
+        }
+    };
+    let name = match symbols.starting_at(addr) {
+        Some(f) => f.name.clone(),
+        None => format!("sub_{:04x}", addr)
+    };
+    let graph = match blocks(mem, dev.cpux, &symbols, addr) {
+        Ok(b) => to_dot(name, b, &symbols),
+        Err(e) => { println!("{}", e); os::set_exit_status(1); return }
+    };
+    let path = path.unwrap_or(format!("{}.dot", name));
+    match File::create(&Path::new(path.clone())).write_str(graph) {
+        Ok(()) => println!("Wrote {}", path),
+        Err(e) => { println!("Could not write {}: {}", path, e); os::set_exit_status(1) }
+    }
+}
@@ -0,0 +1,354 @@
+// Debugger command language
+//
+// One command per line, gdb style: a command word, which may be abbreviated to any
+// unique prefix or one of the usual aliases (s, c, b, x, i, bt, q, ...), then its
+// arguments. Addresses and values are sums and differences of hex numbers (with or
+// without 0x), registers (r15, sp) and function names, e.g. "break login+0x10" or
+// "x/16xb sp+4". Counts are decimal.
+
+use asm;
+use asm::parse_reg;
+
+#[deriving(Clone)]
+pub enum Term {
+    NumTerm(u16),
+    RegTerm(u8),
+    NameTerm(~str)
+}
+
+// terms to add, or subtract if negated
+#[deriving(Clone)]
+pub struct Loc {
+    terms: ~[(bool, Term)]
+}
+
+#[deriving(Clone)]
+pub enum Target {
+    RegTarget(u8),
+    FlagTarget(u16),                //mask of the flag in SR
+    MemTarget(Loc, uint)            //address and unit size in bytes
+}
+
+#[deriving(Clone)]
+pub enum Command {
+    Step(uint),
+    Continue,
+    Animate,                        //continue, drawing every instruction
+    Break(Loc),
+    Delete(Option<Loc>),            //one breakpoint or watchpoint, or all of them
+    Watch(Loc),
+    Examine(uint, char, uint, Loc), //count, format, unit size in bytes, address
+    Set(Target, ~[Loc]),            //one value for registers and flags, any number for memory
+    Write(Loc, ~[u8]),              //set str
+    Fill(Loc, Loc, Loc),            //address, length, byte
+    Edit(Loc),
+    EditRegs,
+    Disas(Option<Loc>),
+    Info(~str),
+    Backtrace,
+    Xrefs(Loc),
+    Patch(~str),                    //[!]ADDR instructions
+    Input(~str),                    //a line for the next getsn
+    Source(~str),                   //a script file
+    Run,
+    Reset,
+    Pages,
+    Heap(Option<Loc>),
+    Redraw,
+    Help(Option<~str>),
+    Quit
+}
+
+// name, arguments, description
+pub static COMMANDS : [(&'static str, &'static str, &'static str), ..24] = [
+    ("step", "[N]", "execute N instructions (default 1)"),
+    ("continue", "", "run until a breakpoint, watchpoint, input or the end"),
+    ("animate", "", "continue, drawing every instruction"),
+    ("break", "LOC", "stop when the pc reaches LOC"),
+    ("delete", "[LOC]", "remove the break- or watchpoint at LOC, or all of them"),
+    ("watch", "LOC", "stop when the word at LOC changes"),
+    ("x", "/NFU LOC", "examine N units (b byte, w word) at LOC as x hex, d decimal, c chars, s string or i instructions"),
+    ("set", "WHAT = VALUE", "change a register (r15), a flag (c, z, n, v) or memory (byte LOC, word LOC, str LOC)"),
+    ("fill", "LOC LEN BYTE", "write LEN copies of BYTE from LOC"),
+    ("edit", "LOC|regs", "edit memory in the RAM pane from LOC, or the registers and flags"),
+    ("disas", "[LOC]", "disassemble the function around LOC (default the pc)"),
+    ("info", "WHAT", "show regs, break, watch, inputs, frames or heap"),
+    ("backtrace", "", "print the call stack and show the stack pane"),
+    ("xrefs", "LOC", "list the references to LOC"),
+    ("asm", "[!]ADDR SRC", "assemble into memory (! ignores DEP)"),
+    ("input", "TEXT", "queue a line for getsn (x-prefixed hex or payload ... work too)"),
+    ("source", "FILE", "run a Lua script driving the debugger"),
+    ("run", "", "reset and continue"),
+    ("reset", "", "restart the program, keeping breakpoints"),
+    ("pages", "", "toggle the DEP page map"),
+    ("heap", "[BASE]", "toggle the heap pane, walking chunks from BASE"),
+    ("redraw", "", "redraw the screen"),
+    ("help", "[COMMAND]", "list the commands, or describe one"),
+    ("quit", "", "leave the debugger")
+];
+
+static ALIASES : [(&'static str, &'static str), ..13] = [
+    ("s", "step"), ("si", "step"), ("stepi", "step"), ("c", "continue"), ("b", "break"),
+    ("d", "delete"), ("i", "info"), ("bt", "backtrace"), ("where", "backtrace"),
+    ("disassemble", "disas"), ("r", "run"), ("h", "help"), ("q", "quit")
+];
+
+pub static INFO : [&'static str, ..6] = ["regs", "break", "watch", "inputs", "frames", "heap"];
+
+pub static FLAGS : [(&'static str, u16), ..4] = [("c", 1), ("z", 1 << 1), ("n", 1 << 2), ("v", 1 << 8)];
+
+static REGNAMES : [&'static str, ..16] = ["pc", "sp", "sr", "cg", "r4", "r5", "r6", "r7", "r8",
+                                          "r9", "r10", "r11", "r12", "r13", "r14", "r15"];
+
+// the one word in `words` that `w` names exactly or abbreviates
+fn expand(w: &str, words: &[&'static str]) -> Result<&'static str, ~str> {
+    match words.iter().find(|c| **c == w) {
+        Some(c) => return Ok(*c),
+        None => ()
+    }
+    let found : ~[&'static str] = words.iter().filter(|c| c.starts_with(w)).map(|c| *c).collect();
+    match found.len() {
+        0 => Err(format!("unknown command {}", w)),
+        1 => Ok(found[0]),
+        _ => Err(format!("{} is ambiguous: {}", w, found.connect(" ")))
+    }
+}
+
+pub fn command_name(w: &str) -> Result<&'static str, ~str> {
+    match ALIASES.iter().find(|&&(a, _)| a == w) {
+        Some(&(_, name)) => return Ok(name),
+        None => ()
+    }
+    let names : ~[&'static str] = COMMANDS.iter().map(|&(n, _, _)| n).collect();
+    expand(w, names)
+}
+
+fn hex(s: &str) -> Option<u16> {
+    let digits = if s.starts_with("0x") || s.starts_with("0X") { s.slice_from(2) } else { s };
+    if digits.len() == 0 || digits.len() > 4 { return None }
+    ::std::u16::parse_bytes(digits.as_bytes(), 16)
+}
+
+fn term(s: &str) -> Result<Term, ~str> {
+    let s = s.trim();
+    if s.len() == 0 { return Err(~"missing value") }
+    match parse_reg(s) {
+        Some(r) => return Ok(RegTerm(r)),
+        None => ()
+    }
+    match hex(s) {
+        Some(n) => Ok(NumTerm(n)),
+        None if s.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '.') => Ok(NameTerm(s.to_owned())),
+        None => Err(format!("bad value {}", s))
+    }
+}
+
+pub fn parse_loc(s: &str) -> Result<Loc, ~str> {
+    let mut terms = ~[];
+    let mut cur = ~"";
+    let mut neg = false;
+    for c in s.trim().chars() {
+        if (c == '+' || c == '-') && terms.len() == 0 && cur.trim().len() == 0 {
+            neg = c == '-';
+        } else if c == '+' || c == '-' {
+            terms.push((neg, try!(term(cur))));
+            cur = ~"";
+            neg = c == '-';
+        } else {
+            cur.push_char(c);
+        }
+    }
+    terms.push((neg, try!(term(cur))));
+    Ok(Loc { terms: terms })
+}
+
+fn optional_loc(args: &str) -> Result<Option<Loc>, ~str> {
+    if args.len() == 0 { Ok(None) } else { parse_loc(args).map(|l| Some(l)) }
+}
+
+// x/NFU: count, format and unit, each optional
+fn examine(spec: &str, args: &str) -> Result<Command, ~str> {
+    let digits : ~str = spec.chars().take_while(|c| c.is_digit()).collect();
+    let count = if digits.len() > 0 { from_str::<uint>(digits).unwrap_or(1) } else { 1 };
+    let (mut format, mut unit) = ('x', 2);
+    for c in spec.slice_from(digits.len()).chars() {
+        match c {
+            'x' | 'd' | 'c' | 's' | 'i' => format = c,
+            'b' => unit = 1,
+            'h' | 'w' => unit = 2,
+            _ => return Err(format!("bad format /{}", spec))
+        }
+    }
+    if format == 'c' && !spec.contains_char('w') && !spec.contains_char('h') { unit = 1 }
+    Ok(Examine(count, format, unit, try!(parse_loc(args))))
+}
+
+pub fn parse(line: &str) -> Result<Command, ~str> {
+    let line = line.trim();
+    let (word, args) = match line.find(|c: char| c.is_whitespace()) {
+        Some(ix) => (line.slice_to(ix), line.slice_from(ix).trim()),
+        None => (line, "")
+    };
+    if word == "x" || word.starts_with("x/") {
+        return examine(if word.len() > 2 { word.slice_from(2) } else { "" }, args)
+    }
+    let name = try!(command_name(word));
+    let needs = |what: &str| if args.len() == 0 { Err(format!("{} needs {}", name, what)) } else { Ok(()) };
+    Ok(match name {
+        "step" if args.len() == 0 => Step(1),
+        "step" => match from_str::<uint>(args) {
+            Some(n) if n > 0 => Step(n),
+            _ => return Err(format!("bad count {}", args))
+        },
+        "continue" => Continue,
+        "animate" => Animate,
+        "break" => { try!(needs("an address")); Break(try!(parse_loc(args))) },
+        "delete" => Delete(try!(optional_loc(args))),
+        "watch" => { try!(needs("an address")); Watch(try!(parse_loc(args))) },
+        "set" => try!(set(args)),
+        "fill" => {
+            let parts : ~[&str] = args.words().collect();
+            if parts.len() != 3 { return Err(~"expected fill LOC LEN BYTE") }
+            Fill(try!(parse_loc(parts[0])), try!(parse_loc(parts[1])), try!(parse_loc(parts[2])))
+        },
+        "edit" if args == "regs" => EditRegs,
+        "edit" => { try!(needs("an address or regs")); Edit(try!(parse_loc(args))) },
+        "disas" => Disas(try!(optional_loc(args))),
+        "info" => { try!(needs(INFO.connect(", "))); Info(try!(expand(args, INFO)).to_owned()) },
+        "backtrace" => Backtrace,
+        "xrefs" => { try!(needs("an address")); Xrefs(try!(parse_loc(args))) },
+        "asm" => { try!(needs("an address and instructions")); Patch(args.to_owned()) },
+        "input" => Input(args.to_owned()),
+        "source" => { try!(needs("a file")); Source(args.to_owned()) },
+        "run" => Run,
+        "reset" => Reset,
+        "pages" => Pages,
+        "heap" => Heap(try!(optional_loc(args))),
+        "redraw" => Redraw,
+        "help" if args.len() == 0 => Help(None),
+        "help" => Help(Some(try!(command_name(args)).to_owned())),
+        "quit" => Quit,
+        _ => return Err(format!("unknown command {}", word))
+    })
+}
+
+// set r15 = 1234, set z = 1, set byte LOC = 41, 42, set word LOC = 4528, set str LOC = "text"
+fn set(args: &str) -> Result<Command, ~str> {
+    let parts : ~[&str] = args.splitn('=', 1).collect();
+    if parts.len() != 2 { return Err(~"expected set WHAT = VALUE") }
+    let (what, value) = (parts[0].trim(), parts[1].trim());
+    let (kind, rest) = match what.find(|c: char| c.is_whitespace()) {
+        Some(ix) => (what.slice_to(ix), what.slice_from(ix).trim()),
+        None => (what, "")
+    };
+    match kind {
+        "byte" | "word" => {
+            let mut values = ~[];
+            for v in value.split(',') { values.push(try!(parse_loc(v))) }
+            Ok(Set(MemTarget(try!(parse_loc(rest)), if kind == "byte" { 1 } else { 2 }), values))
+        },
+        "str" => Ok(Write(try!(parse_loc(rest)), try!(asm::parse_string(value)))),
+        _ => {
+            let target = match (parse_reg(what), FLAGS.iter().find(|&&(f, _)| f == what)) {
+                (Some(r), _) => RegTarget(r),
+                (None, Some(&(_, mask))) => FlagTarget(mask),
+                _ => return Err(format!("cannot set {}", what))
+            };
+            Ok(Set(target, ~[try!(parse_loc(value))]))
+        }
+    }
+}
+
+// the SR flags set in sr, e.g. "c z"
+pub fn flags(sr: u16) -> ~str {
+    let set : ~[&str] = FLAGS.iter().filter(|&&(_, m)| sr & m != 0).map(|&(f, _)| f).collect();
+    set.connect(" ")
+}
+
+// one line per command, or the usage of one
+pub fn help(name: Option<~str>) -> ~[~str] {
+    let mut out = ~[];
+    for &(n, args, text) in COMMANDS.iter() {
+        match name {
+            Some(ref want) if want.as_slice() != n => continue,
+            _ => ()
+        }
+        let aliases : ~[&str] = ALIASES.iter().filter(|&&(_, to)| to == n).map(|&(a, _)| a).collect();
+        let usage = format!("{} {}", n, args);
+        if aliases.len() > 0 {
+            out.push(format!("{:20s} {} ({})", usage, text, aliases.connect(", ")))
+        } else {
+            out.push(format!("{:20s} {}", usage, text))
+        }
+    }
+    out
+}
+
+// the completions of the last word of line: command names first, then info topics,
+// registers and `names`
+pub fn complete(line: &str, names: &[~str]) -> ~[~str] {
+    let (head, last) = match line.rfind(|c: char| c.is_whitespace() || c == '+' || c == '-' || c == '=') {
+        Some(ix) => (line.slice_to(ix + 1), line.slice_from(ix + 1)),
+        None => ("", line)
+    };
+    let is_info = match command_name(head.trim()) { Ok("info") => true, _ => false };
+    let mut words : ~[~str] = if head.trim().len() == 0 {
+        COMMANDS.iter().map(|&(n, _, _)| n.to_owned()).collect()
+    } else if is_info {
+        INFO.iter().map(|w| w.to_owned()).collect()
+    } else {
+        REGNAMES.iter().map(|w| w.to_owned()).collect()
+    };
+    if head.trim().len() > 0 && !is_info { words.push_all(names) }
+    let mut out : ~[~str] = words.move_iter().filter(|w| w.starts_with(last)).map(|w| format!("{}{}", head, w)).collect();
+    out.sort();
+    out.dedup();
+    out
+}
+
+// the longest prefix shared by all of the strings
+pub fn common_prefix(words: &[~str]) -> ~str {
+    if words.len() == 0 { return ~"" }
+    let mut n = words[0].len();
+    for w in words.iter() {
+        n = ::std::cmp::min(n, w.len());
+        while !w.starts_with(words[0].slice_to(n)) { n -= 1 }
+    }
+    words[0].slice_to(n).to_owned()
+}
+
+// lines entered at the console, newest last
+pub struct History {
+    lines: ~[~str],
+    pos: uint               //lines.len() when not browsing
+}
+
+impl History {
+    pub fn new() -> History {
+        History { lines: ~[], pos: 0 }
+    }
+
+    pub fn push(&mut self, line: &str) {
+        if line.trim().len() > 0 && self.lines.last().map_or(true, |l| l.as_slice() != line) {
+            self.lines.push(line.to_owned());
+        }
+        self.pos = self.lines.len();
+    }
+
+    pub fn last(&self) -> Option<~str> {
+        self.lines.last().map(|l| l.clone())
+    }
+
+    pub fn prev(&mut self) -> Option<~str> {
+        if self.pos == 0 { return None }
+        self.pos -= 1;
+        Some(self.lines[self.pos].clone())
+    }
+
+    // the next line, or an empty one past the newest
+    pub fn next(&mut self) -> Option<~str> {
+        if self.pos >= self.lines.len() { return None }
+        self.pos += 1;
+        Some(if self.pos == self.lines.len() { ~"" } else { self.lines[self.pos].clone() })
+    }
+}
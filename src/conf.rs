@@ -0,0 +1,83 @@
+// Minimal INI-style parser shared by the data files (device profiles, ...)
+//
+//   # comment
+//   [section]
+//   key = value
+//
+// Keys may repeat within a section, values keep their order.
+
+pub struct Section {
+    name: ~str,
+    entries: ~[(~str, ~str)]
+}
+
+impl Section {
+    pub fn get(&self, key: &str) -> Option<~str> {
+        for &(ref k, ref v) in self.entries.iter() {
+            if k.as_slice() == key { return Some(v.clone()) }
+        }
+        None
+    }
+
+    pub fn get_all(&self, key: &str) -> ~[~str] {
+        let mut out = ~[];
+        for &(ref k, ref v) in self.entries.iter() {
+            if k.as_slice() == key { out.push(v.clone()) }
+        }
+        out
+    }
+
+    pub fn get_bool(&self, key: &str, default: bool) -> Result<bool, ~str> {
+        match self.get(key) {
+            None => Ok(default),
+            Some(v) => match v.as_slice() {
+                "true" | "yes" | "on" | "1" => Ok(true),
+                "false" | "no" | "off" | "0" => Ok(false),
+                _ => Err(format!("[{}] {}: expected true/false, got '{}'", self.name, key, v))
+            }
+        }
+    }
+
+    pub fn get_num(&self, key: &str) -> Result<Option<u32>, ~str> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(v) => match parse_num(v) {
+                Some(n) => Ok(Some(n)),
+                None => Err(format!("[{}] {}: expected a number, got '{}'", self.name, key, v))
+            }
+        }
+    }
+}
+
+// 0x-prefixed hex or decimal
+pub fn parse_num(s: &str) -> Option<u32> {
+    let s = s.trim();
+    if s.starts_with("0x") || s.starts_with("0X") {
+        ::std::num::from_str_radix::<u32>(s.slice_from(2), 16)
+    } else {
+        from_str::<u32>(s)
+    }
+}
+
+pub fn parse(text: &str) -> Result<~[Section], ~str> {
+    let mut sections : ~[Section] = ~[];
+    for (lineno, raw) in text.lines().enumerate() {
+        let line = raw.trim();
+        if line.len() == 0 || line.starts_with("#") || line.starts_with(";") {
+            continue
+        }
+        if line.starts_with("[") && line.ends_with("]") {
+            sections.push(Section { name: line.slice(1, line.len() - 1).trim().to_owned(), entries: ~[] });
+            continue
+        }
+        let (key, val) = match line.find('=') {
+            Some(ix) => (line.slice_to(ix).trim(), line.slice_from(ix + 1).trim()),
+            None => return Err(format!("line {}: expected 'key = value'", lineno + 1))
+        };
+        match sections.mut_last() {
+            Some(sec) => sec.entries.push((key.to_owned(), val.to_owned())),
+            None => return Err(format!("line {}: entry outside of a [section]", lineno + 1))
+        }
+    }
+    Ok(sections)
+}
@@ -1,7 +1,12 @@
-use mem::{MemUtil, Ram, Regs};
+use mem::{Mem, MemUtil, Ram, Regs, Paged, Fault, DepExec, StackSmash};
+use device::{Device, ImageReset, VectorReset};
+use flash::FlashCtl;
+use stack::Frame;
+use taint;
+use taint::Taint;
 use std::fmt;
 use rand;
-use collections::HashSet;
+use collections::{HashMap, HashSet};
 use ncurses;
 
 mod mem;
@@ -11,6 +16,14 @@ static ZEROF : u16 = 1 << 1;
 static NEGF : u16 = 1 << 2;
 static OVERF : u16 = 1 << 8;
 
+static ADDRMASK : u32 = 0xfffff;
+
+// mnemonics indexed by opcode, shared by the decoder and the assembler
+pub static JUMP_NAMES : [&'static str, ..8] = ["JNE", "JEQ", "JNC", "JC", "JN", "JGE", "JL", "JMP"];
+pub static ONEARG_NAMES : [&'static str, ..7] = ["RRC", "SWPB", "RRA", "SXT", "PUSH", "CALL", "RETI"];
+pub static TWOARG_NAMES : [&'static str, ..16] = ["", "", "", "", "MOV", "ADD", "ADDC", "SUBC",
+                                                  "SUB", "CMP", "DADD", "BIT", "BIC", "BIS", "XOR", "AND"];
+
 // Memory manipulation functions 
 
 pub struct Cpu {
@@ -18,7 +31,17 @@ pub struct Cpu {
     ram: Ram,
     inst: Instruction,
     status: Status,
-    buf: ~str
+    buf: ~str,
+    cpux: bool,
+    callgates: bool,
+    depgates: bool,                 //honour the DEP gates 0x9000/0x9100
+    hsm_password: Option<~[u8]>,    //checked by the HSM-1/HSM-2 call gates
+    frames: ~[Frame],               //shadow call stack, innermost last
+    smashcheck: bool,               //stop when a RET does not match the shadow stack
+    inputs: ~[(u16, ~[u8])],        //buffer and bytes of each getsn
+    taint: Option<~Taint>,          //input taint, when tracked
+    edges: Paged<u8>,               //hit counts of control-flow edges, empty unless tracked
+    steps: uint                     //instructions executed
 }
 
 pub struct Instruction {
@@ -33,23 +56,37 @@ pub struct Instruction {
     srcmode: AddressingMode,
     destreg: u8,
     destmode: AddressingMode,
+    // MSP430X
+    ext: Option<u16>,       //extension word preceding the instruction
+    aw: bool,               //20-bit (.A) operation
+    srchi: u8,              //bits 19:16 of 20-bit source/dest operands
+    desthi: u8,
+    n: u8,                  //register/shift count for PUSHM/POPM and RRxM
+    rpt: u8,                //RPT #n repetitions (minus one)
+    rptreg: Option<u8>,     //RPT Rn
+    zc: bool,
 }
 
-enum OpType {
+pub enum OpType {
     NoArg,
     OneArg,
     TwoArg,
-    Interrupt
+    Interrupt,
+    AddrArg,                //MOVA, CMPA, ADDA, SUBA, RRxM
+    CallA,
+    MultiArg                //PUSHM, POPM
 }
 
+#[deriving(Clone)]
 pub enum Status {
     GetInput(~[u8]),
     Off,
     Success,
+    Faulted(Fault),
     Normal
 }
 
-enum AddressingMode {
+pub enum AddressingMode {
     Direct,
     Indexed(u16),
     Indirect,
@@ -72,9 +109,12 @@ impl fmt::Show for AddressingMode {
     }
 }
 
-fn get_optype(code: u16, pc: u16) -> OpType {
+fn get_optype(code: u16, pc: u16, cpux: bool) -> OpType {
     match (code >> 13, pc) {
         (_,0x10) => Interrupt,
+        (0,_) if cpux && code >> 12 == 0 => AddrArg,
+        (0,_) if cpux && code & 0xff00 == 0x1300 && code & 0xc0 != 0 => CallA,
+        (0,_) if cpux && code & 0xfc00 == 0x1400 => MultiArg,
         (0,_) => OneArg,
         (1,_) => NoArg,
         (_,_) => TwoArg
@@ -83,12 +123,15 @@ fn get_optype(code: u16, pc: u16) -> OpType {
 
 //splitters
 
-fn parse_inst(code: u16, pc: u16) -> Instruction {
-    let optype = get_optype(code, pc);
+fn parse_inst(code: u16, pc: u16, cpux: bool) -> Instruction {
+    let optype = get_optype(code, pc, cpux);
     match optype {
         NoArg => noarg_split(code),
         OneArg => onearg_split(code),
         TwoArg => twoarg_split(code),
+        AddrArg => addr_split(code),
+        CallA => calla_split(code),
+        MultiArg => multi_split(code),
         Interrupt => { 
             let mut i = twoarg_split(0x4130);
             i.optype = Interrupt;
@@ -127,6 +170,76 @@ fn noarg_split(code: u16) -> Instruction {
     inst
 }
 
+fn addr_split(code: u16) -> Instruction {
+    let mut inst = Instruction::new();
+    inst.code = code;
+    inst.optype = AddrArg;
+    inst.srcreg = ((code & 0xf00) >> 8) as u8;
+    inst.destreg = (code & 0xf) as u8;
+    inst.opcode = ((code & 0xf0) >> 4) as u8;
+    inst.aw = true;
+    match inst.opcode {
+        0b0100 | 0b0101 => {
+            //RRCM, RRAM, RLAM, RRUM - srcreg holds which rotate
+            inst.n = ((code & 0xc00) >> 10) as u8 + 1;
+            inst.srcreg = ((code & 0x300) >> 8) as u8;
+            inst.aw = inst.opcode == 0b0100;
+        },
+        _ => ()
+    }
+    inst
+}
+
+fn calla_split(code: u16) -> Instruction {
+    let mut inst = Instruction::new();
+    inst.code = code;
+    inst.optype = CallA;
+    inst.destreg = (code & 0xf) as u8;
+    inst.opcode = ((code & 0xf0) >> 4) as u8;
+    inst.aw = true;
+    inst
+}
+
+fn multi_split(code: u16) -> Instruction {
+    let mut inst = Instruction::new();
+    inst.code = code;
+    inst.optype = MultiArg;
+    inst.destreg = (code & 0xf) as u8;
+    inst.n = ((code & 0xf0) >> 4) as u8 + 1;
+    inst.opcode = ((code & 0x300) >> 8) as u8;
+    inst.aw = inst.opcode & 1 == 0;
+    inst
+}
+
+// merge an MSP430X extension word into the Format I/II instruction that follows it
+fn apply_ext(inst: &mut Instruction, ext: u16) {
+    inst.ext = Some(ext);
+    if ext & 0x40 == 0 && inst.bw {
+        inst.aw = true;
+        inst.bw = false;
+    }
+    let regmode = match inst.optype {
+        TwoArg => inst.code & 0xb0 == 0,
+        OneArg => inst.code & 0x30 == 0,
+        _ => false
+    };
+    if regmode {
+        inst.zc = ext & 0x100 != 0;
+        if ext & 0x80 != 0 {
+            inst.rptreg = Some((ext & 0xf) as u8)
+        } else {
+            inst.rpt = (ext & 0xf) as u8
+        }
+    } else {
+        inst.srchi = ((ext & 0x780) >> 7) as u8;
+        inst.desthi = (ext & 0xf) as u8;
+    }
+}
+
+fn sxt20(val: u16) -> u32 {
+    if val & 0x8000 != 0 { val as u32 | 0xf0000 } else { val as u32 }
+}
+
 impl Cpu {
 
     fn get_addressing_modes(&mut self) {
@@ -138,10 +251,44 @@ impl Cpu {
             OneArg => {
                 self.inst.destmode = self.modes_(self.inst.destreg,((self.inst.code & 0x30) >> 4) as u8);
             }
-            NoArg => ()
+            AddrArg => self.addr_modes(),
+            CallA => {
+                let reg = self.inst.destreg;
+                self.inst.destmode = match self.inst.opcode {
+                    0b0100 => Direct,
+                    0b0101 => Indexed(self.next_inst()),
+                    0b0110 => Indirect,
+                    0b0111 => IndirectInc,
+                    0b1000 => { self.inst.desthi = reg; Absolute(self.next_inst()) },
+                    0b1001 => { self.inst.desthi = reg; self.inst.destreg = 0; Indexed(self.next_inst()) },
+                    0b1011 => { self.inst.desthi = reg; Const(self.next_inst()) },
+                    _ => {
+                        self.buf.push_str(format!("Invalid CALLA mode: {:04x}\n", self.inst.code));
+                        self.status = Off;
+                        Direct
+                    }
+                }
+            },
+            NoArg | MultiArg => ()
         }
     }
 
+    // operand modes of the address-word instructions
+    fn addr_modes(&mut self) {
+        let (src, dest) = match self.inst.opcode {
+            0b0000 => (Indirect, Direct),
+            0b0001 => (IndirectInc, Direct),
+            0b0010 => { self.inst.srchi = self.inst.srcreg; (Absolute(self.next_inst()), Direct) },
+            0b0011 => (Indexed(self.next_inst()), Direct),
+            0b0110 => { self.inst.desthi = self.inst.destreg; (Direct, Absolute(self.next_inst())) },
+            0b0111 => (Direct, Indexed(self.next_inst())),
+            0b1000..0b1011 => { self.inst.srchi = self.inst.srcreg; (Const(self.next_inst()), Direct) },
+            _ => (Direct, Direct)
+        };
+        self.inst.srcmode = src;
+        self.inst.destmode = dest;
+    }
+
     fn modes_(&mut self, reg: u8, modecode: u8) -> AddressingMode {
         match (reg, modecode) {
             (0,0b00) => Direct,
@@ -172,7 +319,9 @@ impl Cpu {
     // memory/register interface
     
     //turn indirects into values
-    fn resolve(&mut self, regadr: u8, mode: AddressingMode) -> u16 {
+    fn resolve(&mut self, regadr: u8, mode: AddressingMode, hi: u8) -> u16 {
+        if self.taint_tracked() { self.taint_read(regadr, mode) }
+        if self.cpux { return self.resolvex(regadr, mode, hi) as u16 }
         let regval = self.regs.load(regadr);
         let mut val = match mode {
             Direct => regval,
@@ -191,7 +340,9 @@ impl Cpu {
         val
     }
 
-    fn _store(&mut self, regadr: u8, mode: AddressingMode, val: u16) {
+    fn _store(&mut self, regadr: u8, mode: AddressingMode, hi: u8, val: u16) {
+        if self.taint_tracked() { self.taint_write(regadr, mode) }
+        if self.cpux { return self.storex(regadr, mode, hi, val as u32) }
         let regval = self.regs.load(regadr);
         let success = match mode {
             Direct => {self.regs.store(regadr, val); true},
@@ -208,16 +359,199 @@ impl Cpu {
             },
             Const(_) => true
         };
-        if !success { ncurses:: endwin(); println!("{:?}", self.ram.deparr);self.status = Off }
+        if !success { self.mem_fault() }
+    }
+
+    // taint follows the 16-bit forms; .A and extension-word instructions are not tracked
+    fn taint_tracked(&self) -> bool {
+        !self.inst.aw && self.inst.ext.is_none()
+    }
+
+    // taint of the operand about to be read
+    fn taint_read(&mut self, regadr: u8, mode: AddressingMode) {
+        let regval = self.regs.load(regadr);
+        let bw = self.inst.bw;
+        match self.taint {
+            Some(ref mut t) => {
+                let labels = match mode {
+                    Direct if bw => [t.regs[regadr][0], None],
+                    Direct => t.regs[regadr],
+                    Indirect | IndirectInc => t.load(regval, bw),
+                    Indexed(offset) => t.load(regval + offset, bw),
+                    Absolute(address) => t.load(address, bw),
+                    Const(_) => [None, None]
+                };
+                t.operands.push(labels)
+            },
+            None => ()
+        }
+    }
+
+    // label the destination with the taint of the result
+    fn taint_write(&mut self, regadr: u8, mode: AddressingMode) {
+        let regval = self.regs.load(regadr);
+        let bw = self.inst.bw;
+        let labels = match self.taint {
+            Some(ref mut t) => {
+                let result = if self.inst.is_call() {
+                    t.regs[0]                                   //CALL pushes the pc
+                } else {
+                    match (self.inst.optype, self.inst.opcode) {
+                        (TwoArg, 0b0100) | (Interrupt, _) => t.operand(0),
+                        (TwoArg, _) => taint::merge(t.operand(0), t.operand(1)),
+                        _ => t.operand(0)
+                    }
+                };
+                let labels = if bw { [result[0], None] } else { result };
+                match mode {
+                    Direct => t.regs[regadr] = labels,
+                    Indirect | IndirectInc => t.store(regval, labels, bw),
+                    Indexed(offset) => t.store(regval + offset, labels, bw),
+                    Absolute(address) => t.store(address, labels, bw),
+                    Const(_) => ()
+                }
+                labels
+            },
+            None => return
+        };
+        match mode {
+            Direct if regadr == 0 => self.taint_pc(labels),
+            _ => ()
+        }
+    }
+
+    fn taint_pc(&mut self, labels: [taint::Label, ..2]) {
+        match taint::describe(labels.as_slice()) {
+            Some(d) => self.buf.push_str(format!("PC loaded from tainted input {}\n", d)),
+            None => ()
+        }
     }
 
     //wrapper
     fn store(&mut self, val: u16) {
-        self._store(self.inst.destreg, self.inst.destmode, val)
+        self._store(self.inst.destreg, self.inst.destmode, self.inst.desthi, val)
+    }
+
+    // 20-bit memory/register interface, used in CPUX mode
+
+    fn width_mask(&self) -> u32 {
+        if self.inst.bw { 0xff } else if self.inst.aw { ADDRMASK } else { 0xffff }
+    }
+
+    fn effective_addr(&self, regadr: u8, mode: AddressingMode, hi: u8) -> u32 {
+        let regval = self.regs.loada(regadr);
+        let hi = hi as u32 << 16;
+        let addr = match mode {
+            Indexed(offset) => {
+                if self.inst.ext.is_some() || hi != 0 { regval + (hi | offset as u32) } else { regval + sxt20(offset) }
+            },
+            Absolute(address) => hi | address as u32,
+            _ => regval
+        };
+        addr & ADDRMASK
+    }
+
+    fn loadx(&self, addr: u32) -> u32 {
+        if self.inst.bw {
+            self.ram.loadbx(addr) as u32
+        } else if self.inst.aw {
+            self.ram.loada(addr)
+        } else {
+            self.ram.loadwx(addr) as u32
+        }
+    }
+
+    fn resolvex(&mut self, regadr: u8, mode: AddressingMode, hi: u8) -> u32 {
+        let regval = self.regs.loada(regadr);
+        //As=11 with R3 is the constant generator's -1; MOVA and CALLA #imm20 keep bits 19:16 in the register field
+        let generated = match self.inst.optype { AddrArg | CallA => false, _ => regadr == 3 };
+        let val = match mode {
+            Direct => regval,
+            Const(0xffff) if generated => ADDRMASK,
+            Const(n) => (hi as u32 << 16) | n as u32,
+            IndirectInc => {
+                let inc = if self.inst.aw { 4 } else { 2 };
+                self.regs.storea(regadr, (regval + inc) & ADDRMASK);
+                self.loadx(regval)
+            },
+            _ => {
+                let addr = self.effective_addr(regadr, mode, hi);
+                self.loadx(addr)
+            }
+        };
+        val & self.width_mask()
+    }
+
+    fn storex(&mut self, regadr: u8, mode: AddressingMode, hi: u8, val: u32) {
+        let success = match mode {
+            Direct => {
+                if self.inst.aw { self.regs.storea(regadr, val & ADDRMASK) } else { self.regs.store(regadr, val as u16) }
+                true
+            },
+            Const(_) => true,
+            _ => {
+                let addr = self.effective_addr(regadr, mode, hi);
+                if self.inst.bw {
+                    self.ram.storebx(addr, val as u8)
+                } else if self.inst.aw {
+                    self.ram.storea(addr, val)
+                } else {
+                    self.ram.storewx(addr, val as u16)
+                }
+            }
+        };
+        if !success { self.mem_fault() }
+    }
+
+    // a store was refused by the memory system
+    fn mem_fault(&mut self) {
+        match self.ram.fault.take() {
+            Some(fault) => self.fault(self.inst.memloc, fault),
+            None => self.status = Off
+        }
+    }
+
+    // stop with a message naming the instruction at fault
+    fn fault(&mut self, pc: u16, fault: Fault) {
+        self.buf.push_str(format!("Fault at pc {:04x}: {}\n", pc, fault));
+        self.status = Faulted(fault)
+    }
+
+    fn pusha(&mut self, val: u32) {
+        let sp = (self.regs.loada(1) - 4) & ADDRMASK;
+        self.regs.storea(1, sp);
+        if !self.ram.storea(sp, val) { self.mem_fault() }
+    }
+
+    fn popa(&mut self) -> u32 {
+        let sp = self.regs.loada(1);
+        self.regs.storea(1, (sp + 4) & ADDRMASK);
+        self.ram.loada(sp)
     }
 
     //execution stage
     fn exec(&mut self) {
+        match self.inst.optype {
+            AddrArg => return self.exec_addr(),
+            CallA => return self.exec_calla(),
+            MultiArg => return self.exec_multi(),
+            _ => ()
+        }
+        let reps = match self.inst.rptreg {
+            Some(r) => self.regs.load(r) & 0xf,
+            None => self.inst.rpt as u16
+        };
+        for _ in range(0, reps + 1) {
+            if self.inst.zc { self.set_flag(CARRYF, false) }
+            if self.inst.aw { self.exec_a() } else { self.exec_() }
+        }
+    }
+
+    fn exec_(&mut self) {
+        match self.taint {
+            Some(ref mut t) => t.operands.clear(),
+            None => ()
+        }
         match (self.inst.optype,self.inst.opcode) {
             (NoArg,0b000) => self.noarg_dispatch(JNE),
             (NoArg,0b001) => self.noarg_dispatch(JEQ),
@@ -249,45 +583,215 @@ impl Cpu {
             (Interrupt,_) => self.handle_interrupt(),
             _ => fail!("Illegal opcode")
         }
+        if self.inst.is_call() {
+            //the pc now holds the call target
+            let labels = match self.taint {
+                Some(ref mut t) => { t.regs[0] = t.operand(0); t.regs[0] },
+                None => return
+            };
+            self.taint_pc(labels)
+        }
     }
 
     fn noarg_dispatch(&mut self, f: fn(&Cpu) -> bool) {
-        if f(self) { self.regs.arr[0] = self.regs.arr[0] + self.inst.offset }
+        if f(self) {
+            if self.cpux {
+                let pc = self.regs.loada(0);
+                self.regs.storea(0, (pc + sxt20(self.inst.offset)) & ADDRMASK)
+            } else {
+                self.regs.arr[0] = self.regs.arr[0] + self.inst.offset
+            }
+        }
     }
 
     fn onearg_dispatch(&mut self, f: fn(&mut Cpu, val: u16)) {
-        let val = self.resolve(self.inst.destreg, self.inst.destmode);
+        let val = self.resolve(self.inst.destreg, self.inst.destmode, self.inst.desthi);
         f(self, val)
     }
 
     fn twoarg_dispatch(&mut self, f: fn(&mut Cpu, val: u16, inc:u16)) {
-        let inc = self.resolve(self.inst.srcreg, self.inst.srcmode);
-        let val = self.resolve(self.inst.destreg, self.inst.destmode);
+        let inc = self.resolve(self.inst.srcreg, self.inst.srcmode, self.inst.srchi);
+        let val = self.resolve(self.inst.destreg, self.inst.destmode, self.inst.desthi);
         f(self, val, inc)
     }
 
+    // .A variants of the Format I/II instructions
+    fn exec_a(&mut self) {
+        let (dreg, dmode, dhi) = (self.inst.destreg, self.inst.destmode, self.inst.desthi);
+        match (self.inst.optype, self.inst.opcode) {
+            (OneArg,0b100) => {                                             //PUSHX.A
+                let val = self.resolvex(dreg, dmode, dhi);
+                self.pusha(val)
+            },
+            (OneArg,op) => {
+                let val = self.resolvex(dreg, dmode, dhi);
+                let c = self.getflag(CARRYF);
+                let res = match op {
+                    0b000 => (val >> 1) | if c { 0x80000 } else { 0 },     //RRCX.A
+                    0b001 => ((val & 0xff) << 8) | ((val >> 8) & 0xff),     //SWPBX.A
+                    0b010 => (val >> 1) | (val & 0x80000),                  //RRAX.A
+                    0b011 => if val & 0x80 != 0 { val | 0xfff00 } else { val & 0xff }, //SXTX.A
+                    _ => { self.unsupported(); return }
+                };
+                if op != 0b001 { self.set_flag(CARRYF, val & 1 != 0) }
+                self.setzn_a(res);
+                self.storex(dreg, dmode, dhi, res & ADDRMASK)
+            },
+            (TwoArg,op) => {
+                let src = self.resolvex(self.inst.srcreg, self.inst.srcmode, self.inst.srchi);
+                let dest = self.resolvex(dreg, dmode, dhi);
+                let c = if self.getflag(CARRYF) { 1 } else { 0 };
+                let (res, carry) = match op {
+                    0b0100 => (src, None),                                          //MOVX.A
+                    0b0101 => (dest + src, Some(dest + src > ADDRMASK)),            //ADDX.A
+                    0b0110 => (dest + src + c, Some(dest + src + c > ADDRMASK)),    //ADDCX.A
+                    0b0111 | 0b1000 | 0b1001 => {                                   //SUBCX.A, SUBX.A, CMPX.A
+                        let borrow = if op == 0b0111 { c } else { 1 };
+                        let v = dest + (!src & ADDRMASK) + borrow;
+                        (v, Some(v > ADDRMASK))
+                    },
+                    0b1011 => (dest & src, Some(dest & src != 0)),                  //BITX.A
+                    0b1100 => (dest & !src, None),                                  //BICX.A
+                    0b1101 => (dest | src, None),                                   //BISX.A
+                    0b1110 => (dest ^ src, Some(dest ^ src != 0)),                  //XORX.A
+                    0b1111 => (dest & src, Some(dest & src != 0)),                  //ANDX.A
+                    _ => { self.unsupported(); return }
+                };
+                let res = res & ADDRMASK;
+                match carry {
+                    Some(cf) => { self.setzn_a(res); self.set_flag(CARRYF, cf) },
+                    None => ()
+                }
+                if op != 0b1001 && op != 0b1011 { self.storex(dreg, dmode, dhi, res) }
+            },
+            _ => self.unsupported()
+        }
+    }
+
+    // MOVA, CMPA, ADDA, SUBA, RRxM
+    fn exec_addr(&mut self) {
+        let (dreg, dmode, dhi) = (self.inst.destreg, self.inst.destmode, self.inst.desthi);
+        let op = self.inst.opcode;
+        match op {
+            0b0100 | 0b0101 => self.rotate_m(),
+            0b1001 | 0b1010 | 0b1011 | 0b1101 | 0b1110 | 0b1111 => {
+                let src = self.resolvex(self.inst.srcreg, self.inst.srcmode, self.inst.srchi);
+                let dest = self.regs.loada(dreg);
+                let res = match op & 0b11 {
+                    0b10 => dest + src,                                     //ADDA
+                    _ => dest + (!src & ADDRMASK) + 1                       //CMPA, SUBA
+                };
+                self.setzn_a(res & ADDRMASK);
+                self.set_flag(CARRYF, res > ADDRMASK);
+                if op & 0b11 != 0b01 { self.regs.storea(dreg, res & ADDRMASK) }
+            },
+            _ => {                                                          //MOVA
+                let val = self.resolvex(self.inst.srcreg, self.inst.srcmode, self.inst.srchi);
+                self.storex(dreg, dmode, dhi, val)
+            }
+        }
+    }
+
+    fn rotate_m(&mut self) {
+        let reg = self.inst.destreg;
+        let (mask, msb) = if self.inst.aw { (ADDRMASK, 0x80000) } else { (0xffff, 0x8000) };
+        let mut val = self.regs.loada(reg) & mask;
+        for _ in range(0, self.inst.n) {
+            let c = self.getflag(CARRYF);
+            match self.inst.srcreg {
+                0 => { self.set_flag(CARRYF, val & 1 != 0); val = (val >> 1) | if c { msb } else { 0 } },   //RRCM
+                1 => { self.set_flag(CARRYF, val & 1 != 0); val = (val >> 1) | (val & msb) },               //RRAM
+                2 => { self.set_flag(CARRYF, val & msb != 0); val = (val << 1) & mask },                   //RLAM
+                _ => { self.set_flag(CARRYF, val & 1 != 0); val >>= 1 }                                     //RRUM
+            }
+        }
+        self.set_flag(ZEROF, val == 0);
+        self.set_flag(NEGF, val & msb != 0);
+        if self.inst.aw { self.regs.storea(reg, val) } else { self.regs.store(reg, val as u16) }
+    }
+
+    fn exec_calla(&mut self) {
+        let target = self.resolvex(self.inst.destreg, self.inst.destmode, self.inst.desthi);
+        let pc = self.regs.loada(0);
+        self.pusha(pc);
+        self.regs.storea(0, target & ADDRMASK)
+    }
+
+    // PUSHM pushes Rdst down to Rdst-n+1, POPM pops from the encoded register upwards
+    fn exec_multi(&mut self) {
+        let mut reg = self.inst.destreg;
+        let fits = match self.inst.opcode {
+            0 | 1 => self.inst.n as uint <= reg as uint + 1,
+            _ => reg as uint + self.inst.n as uint <= 16
+        };
+        if !fits { return self.unsupported() }
+        for _ in range(0, self.inst.n) {
+            match self.inst.opcode {
+                0 => { let val = self.regs.loada(reg); self.pusha(val); reg -= 1 },
+                1 => {
+                    let sp = (self.regs.loada(1) - 2) & ADDRMASK;
+                    self.regs.storea(1, sp);
+                    let val = self.regs.load(reg);
+                    if !self.ram.storewx(sp, val) { self.mem_fault() }
+                    reg -= 1
+                },
+                2 => { let val = self.popa(); self.regs.storea(reg, val); reg += 1 },
+                _ => {
+                    let sp = self.regs.loada(1);
+                    self.regs.storea(1, (sp + 2) & ADDRMASK);
+                    let val = self.ram.loadwx(sp);
+                    self.regs.store(reg, val);
+                    reg += 1
+                }
+            }
+        }
+    }
+
+    fn unsupported(&mut self) {
+        self.buf.push_str(format!("Instruction not implemented: {}\n", self.inst.to_string()));
+        self.status = Off
+    }
+
     fn handle_interrupt(&mut self) {
         match self.regs.arr[2] {            //sr register
-            0x8000 => { self.buf.push_char(self.ram.arr[self.regs.arr[1]+8] as char); } // putsn
+            0x8000 => { self.buf.push_char(self.ram.loadb(self.regs.arr[1]+8) as char); } // putsn
             0x8200 => { self.buf.push_char('\n'); self.status = GetInput(~[]); return },                          //getsn 
             0xff00 => { self.status = Success }                                         //unlock
-            0xfd00 => {                                                                 //try unlock
-                // assume fail, put zero in location
-                let storeloc = self.ram.loadw(self.regs.arr[1] + 8);
-                self.ram.store(storeloc, 0, true);
+            0xfd00 => {                                                                 //HSM-1: set flag if password ok
+                let pwloc = self.ram.loadw(self.regs.arr[1] + 8);
+                let flagloc = self.ram.loadw(self.regs.arr[1] + 10);
+                let ok = self.hsm_check(pwloc);
+                self.ram.store(flagloc, if ok { 1 } else { 0 }, true);
+            },
+            0xfe00 => {                                                                 //HSM-2: unlock if password ok
+                let pwloc = self.ram.loadw(self.regs.arr[1] + 8);
+                if self.hsm_check(pwloc) { self.status = Success }
             },
-            0xfe00 => (),                                                               //try unlock 2
-            0x9100 => {                                                                 //set writable/exec only
+            0x9100 if self.depgates => {                                                //set writable/exec only
                 let addr = self.ram.loadw(self.regs.load(1) + 8);
                 let writable = self.ram.loadw(self.regs.load(1) + 10) > 0;
                 self.ram.deparr[addr] = writable;},
-            0x9000 => { self.ram.depstatus = true },                                   //turn on dep
+            0x9000 if self.depgates => { self.ram.depstatus = true },                  //turn on dep
+            0x9000 | 0x9100 => (),
             0xa000 => { self.regs.store(15, rand::random::<u16>()); }                   //random
             w => { self.buf.push_str(format!("Interrupt not implemented: {:04x}\n",w));
                    self.status = Off; }
         }
         self.twoarg_dispatch(MOV)
     }
+
+    // compare the NUL-terminated string at addr with the password stored in the HSM
+    fn hsm_check(&self, addr: u16) -> bool {
+        match self.hsm_password {
+            None => false,
+            Some(ref pw) => {
+                for (ix, &byte) in pw.iter().enumerate() {
+                    if self.ram.loadb(addr + ix as u16) != byte { return false }
+                }
+                self.ram.loadb(addr + pw.len() as u16) == 0
+            }
+        }
+    }
 }
 
 
@@ -332,7 +836,7 @@ fn SXT(cpu:&mut Cpu, val: u16) {
 
 fn PUSH(cpu:&mut Cpu, val: u16) {
     cpu.regs.arr[1] -= 2;
-    cpu._store(1, Indirect, val);        //push 
+    cpu._store(1, Indirect, 0, val);        //push 
 }
 
 //XXX: broken
@@ -341,7 +845,7 @@ fn CALL(cpu:&mut Cpu,val: u16) {
     cpu.inst.destreg = 0;
     cpu.inst.destmode = Direct;
     PUSH(cpu,cpu.regs.arr[0]); // push pc to stack 
-    cpu.regs.arr[0] = val
+    cpu.regs.store(0, val)
 }
 
 fn RETI(_:&mut Cpu, _: u16) {
@@ -412,6 +916,11 @@ impl Cpu {
         self.set_flag(NEGF, val & 0x8000 != 0);
     }
 
+    fn setzn_a(&mut self, val: u32) {
+        self.set_flag(ZEROF, val & ADDRMASK == 0);
+        self.set_flag(NEGF, val & 0x80000 != 0);
+    }
+
     fn set_and_store(&mut self, val: u16) {
         self.setzn(val);
         self.store(val);
@@ -419,6 +928,11 @@ impl Cpu {
 
     // load instruction from ram and increment pc
     fn next_inst(&mut self) -> u16 {
+        if self.cpux {
+            let pc = self.regs.loada(0);
+            self.regs.storea(0, (pc + 2) & ADDRMASK);
+            return self.ram.loadwx(pc)
+        }
         let inst = self.ram.loadw(self.regs.arr[0]);
         self.regs.arr[0] += 2;
         if !self.regs.arr[0] % 2 == 0 {
@@ -434,41 +948,186 @@ impl Cpu {
         let mut b = ~[];
         match self.status {
             Normal => {
+                let pc = self.inst.memloc;
+                let next = self.regs.arr[0];        //past the instruction and its extension words
+                let sp = self.regs.arr[1];
+                self.ram.pc = pc;
+                self.steps += 1;
                 self.exec();
+                match self.status {
+                    Faulted(_) => return,
+                    _ => ()
+                }
+                if self.inst.is_call() {
+                    self.push_frame(pc, next)
+                } else if self.inst.is_return() && self.regs.arr[1] != sp {
+                    //(the getsn gate returns only once input arrives)
+                    self.pop_frame(pc, sp)
+                }
+                if self.edges.len() > 0 && (self.inst.is_jump() || self.regs.arr[0] != next) {
+                    self.record_edge(pc, self.regs.arr[0])
+                }
                 self.prepare_next();
                 if self.regs.arr[2] & 0x80 != 0 { self.status = Off } // CPU OFF
+                let target = self.inst.memloc;
+                match self.status {
+                    Normal if self.ram.depstatus && self.ram.deparr[target >> 8] => {
+                        self.fault(pc, DepExec(target as u32))
+                    },
+                    _ => ()
+                }
             },
-            Off | Success => (),
+            Off | Success | Faulted(_) => (),
             GetInput(ref bytes) => b = bytes.clone()
         }
-        if self.ram.depstatus && self.ram.deparr[self.regs.arr[0] >> 16] { self.status = Off }
         if b != ~[] {
             self.getsn(b);
             //prepare next instruction
             self.status = Normal;
-            self.inst =  parse_inst(0x4130,0);
+            self.inst =  parse_inst(0x4130, 0, self.cpux);
             self.get_addressing_modes();
         }
     }
 
+    // edge coverage for the fuzzer, kept as AFL does: one saturating counter per
+    // (from, to) pair, hashed into 64KiB; counting starts again from zero
+    pub fn track_edges(&mut self) {
+        self.edges = Paged::new(0x10000, 0u8);
+    }
+
+    fn record_edge(&mut self, from: u16, to: u16) {
+        let ix = ((from >> 1) ^ to) as uint;
+        let n = self.edges.get(ix);
+        if n < 0xff { self.edges.set(ix, n + 1) }
+    }
+
+    // the edges hit since track_edges() or share(), with their counts
+    pub fn edge_counts(&self) -> ~[(u16, u8)] {
+        self.edges.written().move_iter().filter(|&(_, n)| n > 0).map(|(ix, n)| (ix as u16, n)).collect()
+    }
+
+    // fold memory, edge counts and taint into shared bases, so clones are cheap
+    pub fn share(&mut self) {
+        self.ram.share();
+        self.edges.share();
+        match self.taint {
+            Some(ref mut t) => t.mem.share(),
+            None => ()
+        }
+    }
+
+    pub fn enable_taint(&mut self) {
+        self.taint = Some(~Taint::new());
+    }
+
+    // stop on a corrupted return address, recording writers to explain it
+    pub fn enable_smash_check(&mut self) {
+        self.smashcheck = true;
+        self.ram.track_writes();
+    }
+
+    // shadow call stack, checked against the real return addresses
+    fn push_frame(&mut self, pc: u16, ret: u16) {
+        let target = self.regs.arr[0];
+        self.frames.push(Frame { call: pc, target: target, ret: ret, slot: self.regs.arr[1],
+                                 gate: self.callgates && target == 0x10 });
+    }
+
+    fn pop_frame(&mut self, pc: u16, slot: u16) {
+        //frames abandoned by code that unwound the stack without returning
+        while self.frames.last().map_or(false, |f| f.slot < slot) { self.frames.pop(); }
+        let top = match self.frames.last() {
+            Some(&f) if f.slot == slot => f,
+            _ => return
+        };
+        let target = self.regs.arr[0];
+        if target != top.ret {
+            self.buf.push_str(format!("Return to {:04x} from {:04x}, called from {:04x} to return to {:04x}: stack smashed?\n",
+                                      target, top.target, top.call, top.ret));
+            if self.smashcheck { self.report_smash(pc, top.slot, top.ret, target) }
+        }
+        self.frames.pop();
+    }
+
+    // who overwrote the return address, and with which input bytes
+    fn report_smash(&mut self, pc: u16, slot: u16, expected: u16, actual: u16) {
+        for ix in range(0u16, 2) {
+            match self.ram.last_writer(slot + ix) {
+                Some(w) => self.buf.push_str(format!("Byte {:04x} last written by {:04x}\n", slot + ix, w)),
+                None => ()
+            }
+        }
+        let bytes = [(actual & 0xff) as u8, (actual >> 8) as u8];
+        match self.find_input(bytes) {
+            Some((n, offset, addr)) => self.buf.push_str(format!("{:02x} {:02x} are input {} bytes {}-{} (at {:04x})\n",
+                                                                bytes[0], bytes[1], n + 1, offset, offset + 1, addr)),
+            None => self.buf.push_str(~"Return address does not appear in the input\n")
+        }
+        self.fault(pc, StackSmash(slot, expected, actual))
+    }
+
+    // (input number, offset, address) of the most recent input containing bytes
+    fn find_input(&self, bytes: &[u8]) -> Option<(uint, uint, u16)> {
+        for (n, &(buf, ref input)) in self.inputs.iter().enumerate().rev() {
+            for offset in range(0, input.len()) {
+                if input.slice_from(offset).starts_with(bytes) { return Some((n, offset, buf + offset as u16)) }
+            }
+        }
+        None
+    }
+
+    // (buffer, length) of a getsn waiting for input
+    pub fn pending_input(&self) -> Option<(u16, u16)> {
+        match self.status {
+            GetInput(ref bytes) if bytes.len() == 0 => {
+                let sp = self.regs.arr[1];
+                Some((self.ram.loadw(sp + 8), self.ram.loadw(sp + 10)))
+            },
+            _ => None
+        }
+    }
+
     fn getsn(&mut self, bytes: ~[u8]) {
         let sp = self.regs.arr[1];
         let putloc = self.ram.loadw(sp + 8);
         let mut getn = self.ram.loadw(sp + 10);
         if (bytes.len() as u16) < getn { getn = bytes.len() as u16 }
+        self.ram.pc = self.inst.memloc;
         for i in range(0, getn) {
-            self.ram.arr[putloc + (i as u16)] =  bytes[i];
+            self.ram.poke((putloc + i) as u32, bytes[i]);
+            self.ram.record_write((putloc + i) as u32);
+            match self.taint {
+                Some(ref mut t) => t.mem.set((putloc + i) as uint, Some(i)),
+                None => ()
+            }
         }
+        self.inputs.push((putloc, bytes.slice_to(getn as uint).to_owned()));
     }
 
     fn prepare_next(&mut self) {
         let pc = self.regs.arr[0];
-        let code = self.next_inst();
-        self.inst = parse_inst(code, pc);
+        let mut code = self.next_inst();
+        let mut ext = None;
+        if self.cpux && code & 0xf800 == 0x1800 {
+            ext = Some(code);
+            code = self.next_inst();
+        }
+        //the pc is only used to spot the call gate
+        let gatepc = if self.callgates { pc } else { 0 };
+        self.inst = parse_inst(code, gatepc, self.cpux);
+        match ext {
+            Some(e) => apply_ext(&mut self.inst, e),
+            None => ()
+        }
         self.get_addressing_modes();
         self.inst.memloc = pc;
     }
 
+    // decode the pending instruction again, after memory under it was patched
+    pub fn refetch(&mut self) {
+        self.regs.store(0, self.inst.memloc);
+        self.prepare_next();
+    }
 
     pub fn new() -> Cpu { 
         Cpu {
@@ -476,21 +1135,67 @@ impl Cpu {
             ram: Ram::new(),
             inst: Instruction::new(),
             status: Normal,
-            buf: ~""
+            buf: ~"",
+            cpux: false,
+            callgates: true,
+            depgates: true,
+            hsm_password: None,
+            frames: ~[],
+            smashcheck: false,
+            inputs: ~[],
+            taint: None,
+            edges: Paged::empty(),
+            steps: 0
         }
     }
 
 
-    pub fn init(image: &[u8]) -> Cpu {
+    pub fn init(image: &[u8], dev: &Device) -> Cpu {
         let mut cpu = Cpu::new();
-        cpu.ram.loadimage(image, 0x4400);
-        cpu.regs.arr[0] = 0x4400;
+        if dev.cpux {
+            cpu.cpux = true;
+            cpu.ram.enable_ext();
+        }
+        cpu.callgates = dev.callgates;
+        cpu.ram.loadimage(image, dev.load);
+        cpu.ram.map = dev.regions.clone();
+        if dev.has_peripheral("flashctl") {
+            cpu.ram.enable_flash(FlashCtl::new(dev.fctl, dev.infoseg));
+        }
+        let start = match dev.reset {
+            ImageReset => dev.load,
+            VectorReset => cpu.ram.loadw(0xfffe) as u32
+        };
+        cpu.regs.storea(0, start);
         cpu.prepare_next();
         cpu
     }
 
 }
 
+// a snapshot to restart from, e.g. for the fuzzer
+impl Clone for Cpu {
+    fn clone(&self) -> Cpu {
+        Cpu {
+            regs: self.regs,
+            ram: self.ram.clone(),
+            inst: self.inst,
+            status: self.status.clone(),
+            buf: self.buf.clone(),
+            cpux: self.cpux,
+            callgates: self.callgates,
+            depgates: self.depgates,
+            hsm_password: self.hsm_password.clone(),
+            frames: self.frames.clone(),
+            smashcheck: self.smashcheck,
+            inputs: self.inputs.clone(),
+            taint: self.taint.clone(),
+            edges: self.edges.clone(),
+            steps: self.steps
+        }
+    }
+}
+
 impl fmt::Show for Cpu {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f.buf,
@@ -513,94 +1218,323 @@ impl Instruction {
             srcmode: Direct,
             destreg: 0,
             srcreg: 0,
+            ext: None,
+            aw: false,
+            srchi: 0,
+            desthi: 0,
+            n: 0,
+            rpt: 0,
+            rptreg: None,
+            zc: false,
+        }
+    }
+
+    // (mode, register, written) of each operand
+    pub fn operands(&self) -> ~[(AddressingMode, u8, bool)] {
+        match self.optype {
+            TwoArg => {
+                let written = self.opcode != 0b1001 && self.opcode != 0b1011;      //CMP, BIT
+                ~[(self.srcmode, self.srcreg, false), (self.destmode, self.destreg, written)]
+            },
+            AddrArg => {
+                let written = self.opcode & 0b1011 != 0b1001;                     //CMPA
+                ~[(self.srcmode, self.srcreg, false), (self.destmode, self.destreg, written)]
+            },
+            OneArg => ~[(self.destmode, self.destreg, self.opcode <= 0b011)],   //RRC, SWPB, RRA, SXT
+            CallA => ~[(self.destmode, self.destreg, false)],
+            _ => ~[]
         }
     }
 
-    fn namer(&self) -> ~str {
+    pub fn is_jump(&self) -> bool {
+        match self.optype {
+            NoArg => true,
+            _ => false
+        }
+    }
+
+    // RET, RETA and the return from a call gate
+    pub fn is_return(&self) -> bool {
+        match (self.optype, self.opcode, self.srcmode, self.destmode) {
+            (Interrupt, _, _, _) => true,
+            (TwoArg, 0b0100, IndirectInc, Direct) | (AddrArg, 0b0001, IndirectInc, Direct) => {
+                self.srcreg == 1 && self.destreg == 0
+            },
+            _ => false
+        }
+    }
+
+    pub fn is_call(&self) -> bool {
         match (self.optype, self.opcode) {
-            (NoArg,0b000) => ~"JNE",
-            (NoArg,0b001) => ~"JEQ",
-            (NoArg,0b010) => ~"JNC",
-            (NoArg,0b011) => ~"JC",
-            (NoArg,0b100) => ~"JN",
-            (NoArg,0b101) => ~"JGE",
-            (NoArg,0b110) => ~"JL",
-            (NoArg,0b111) => ~"JMP",
-            (OneArg,0b000) => ~"RRC",
-            (OneArg,0b001) => ~"SWPB",
-            (OneArg,0b010) => ~"RRA",
-            (OneArg,0b011) => ~"SXT",
-            (OneArg,0b100) => ~"PUSH",
-            (OneArg,0b101) => ~"CALL",
-            (OneArg,0b110) => ~"RETI",
-            (TwoArg,0b0100) => ~"MOV",
-            (TwoArg,0b0101) => ~"ADD",
-            (TwoArg,0b0110) => ~"ADDC",
-            (TwoArg,0b0111) => ~"SUBC",
-            (TwoArg,0b1000) => ~"SUB",
-            (TwoArg,0b1001) => ~"CMP",
-            (TwoArg,0b1010) => ~"DADD",
-            (TwoArg,0b1011) => ~"BIT",
-            (TwoArg,0b1100) => ~"BIC",
-            (TwoArg,0b1101) => ~"BIS",
-            (TwoArg,0b1110) => ~"XOR",
-            (TwoArg,0b1111) => ~"AND",
+            (OneArg, 5) | (CallA, _) => true,
+            _ => false
+        }
+    }
+
+    // where execution can continue after this instruction, given the address following it
+    pub fn flow(&self, next: u16) -> ~[u16] {
+        let writes_pc = self.destreg == 0 && match self.destmode { Direct => true, _ => false };
+        match (self.optype, self.opcode) {
+            (NoArg, 7) => ~[next + self.offset],                       //JMP
+            (NoArg, _) => ~[next + self.offset, next],
+            (OneArg, 5) | (CallA, _) => match self.destmode {           //CALL
+                Const(n) if self.destreg == 0 => ~[n, next],
+                _ => ~[next]
+            },
+            (OneArg, 6) | (Interrupt, _) => ~[],                        //RETI, call gate
+            (TwoArg, 4) if writes_pc => match self.srcmode {            //BR, RET
+                Const(n) if self.srcreg == 0 => ~[n],
+                _ => ~[]
+            },
+            (TwoArg, _) | (AddrArg, _) if writes_pc => ~[],
+            _ => ~[next]
+        }
+    }
+
+    pub fn namer(&self) -> ~str {
+        match (self.optype, self.opcode) {
+            (NoArg,op) => JUMP_NAMES[op].to_owned(),
+            (OneArg,op @ 0b000..0b110) => ONEARG_NAMES[op].to_owned(),
+            (TwoArg,op @ 0b0100..0b1111) => TWOARG_NAMES[op].to_owned(),
             (Interrupt,_) => ~"INT",
+            (AddrArg,0b0100) | (AddrArg,0b0101) => match self.srcreg {
+                0 => ~"RRCM",
+                1 => ~"RRAM",
+                2 => ~"RLAM",
+                _ => ~"RRUM"
+            },
+            (AddrArg,0b1001) | (AddrArg,0b1101) => ~"CMPA",
+            (AddrArg,0b1010) | (AddrArg,0b1110) => ~"ADDA",
+            (AddrArg,0b1011) | (AddrArg,0b1111) => ~"SUBA",
+            (AddrArg,_) => ~"MOVA",
+            (CallA,_) => ~"CALLA",
+            (MultiArg,0) | (MultiArg,1) => ~"PUSHM",
+            (MultiArg,_) => ~"POPM",
             (_,_) => ~"UNKNOWN",
         }
     }
 
     pub fn to_string(&self) -> ~str {
-        let op = self.namer();
-        let byte = if self.bw { ~".B" } else { ~"" };
+        let ops = self.operand_text();
+        let a1 = if ops.len() > 0 { ops[0].clone() } else { ~"" };
+        let a2 = if ops.len() > 1 { ops[1].clone() } else { ~"" };
+        format!("{:s} {:s} {:s}", self.mnemonic(), a1, a2)
+    }
+
+    // name with the X suffix, width and RPT prefix
+    pub fn mnemonic(&self) -> ~str {
+        let mut op = self.namer();
+        if self.ext.is_some() { op.push_str("X") }
+        let byte = match self.optype {
+            _ if self.bw => ~".B",
+            OneArg | TwoArg | MultiArg if self.aw => ~".A",
+            AddrArg if self.aw && self.opcode & 0b1110 == 0b0100 => ~".A",
+            _ => ~""
+        };
+        let rpt = match (self.rptreg, self.rpt) {
+            (Some(r), _) => format!("RPT r{:u} ", r),
+            (None, 0) => ~"",
+            (None, n) => format!("RPT \\#{:u} ", n + 1)
+        };
+        format!("{:s}{:s}{:s}", rpt, op, byte)
+    }
+
+    pub fn operand_text(&self) -> ~[~str] {
         let (a1, a2) = match self.optype {
             NoArg => (format!("\\#0x{:04x}", self.offset + 2), ~""),
-            OneArg => (optype_formatter(self.destmode, self.destreg), ~""),
-            TwoArg => (optype_formatter(self.srcmode, self.srcreg),
-                       optype_formatter(self.destmode, self.destreg)),
-            Interrupt => (~"",~"")
-
+            OneArg => (optype_formatter(self.destmode, self.destreg, self.desthi), ~""),
+            TwoArg => (optype_formatter(self.srcmode, self.srcreg, self.srchi),
+                       optype_formatter(self.destmode, self.destreg, self.desthi)),
+            Interrupt => (~"",~""),
+            AddrArg if self.opcode & 0b1110 == 0b0100 => (format!("\\#{:u}", self.n), format!("r{:u}", self.destreg)),
+            AddrArg => (optype_formatter(self.srcmode, self.srcreg, self.srchi),
+                        optype_formatter(self.destmode, self.destreg, self.desthi)),
+            CallA => (optype_formatter(self.destmode, self.destreg, self.desthi), ~""),
+            MultiArg => {
+                let top = if self.opcode >= 2 { self.destreg + self.n - 1 } else { self.destreg };
+                (format!("\\#{:u}", self.n), format!("r{:u}", top))
+            }
         };
-        format!("{:s}{:s} {:s} {:s}", op, byte, a1, a2)
+        (~[a1, a2]).move_iter().filter(|a| a.len() > 0).collect()
     }
 }
 
-fn optype_formatter(mode: AddressingMode, reg: u8) -> ~str {
+fn optype_formatter(mode: AddressingMode, reg: u8, hi: u8) -> ~str {
+    let hi = hi as u32 << 16;
     match mode {
         Direct => format!("r{:u}", reg),
         Indirect => format!("@r{:u}", reg),
         IndirectInc => format!("@r{:u}+", reg),
-        Absolute(address) => format!("&0x{:x}", address),
-        Indexed(offset) => format!("(0x{:x})r{:u}", offset, reg),
-        Const(n) => format!("{:x}", n)
+        Absolute(address) => format!("&0x{:x}", hi | address as u32),
+        Indexed(offset) => format!("(0x{:x})r{:u}", hi | offset as u32, reg),
+        Const(n) => format!("{:x}", hi | n as u32)
     }
 }
 
 
-pub fn disassemble(v: &[u8]) -> ~[(u16, ~str)] {
+// Code reachable from the start of the image and the interrupt vectors, following
+// jumps, calls and fallthrough.
+pub struct Trace {
+    insts: HashMap<u16, (Instruction, u16)>,    //decoded instruction and its length
+    owned: ~[bool],                             //bytes covered by insts
+    blocks: HashSet<u16>,                       //16-byte blocks holding nonzero bytes
+    entries: ~[u16],
+    first: u16
+}
+
+pub fn trace(v: &[u8], cpux: bool) -> Option<Trace> {
+    let mut c = Cpu::new();
+    c.cpux = cpux;
+    c.ram.loadimage(v,0);
+    c.trace()
+}
+
+// Whatever the trace reaches is code. Unreached gaps are swept linearly if they decode
+// cleanly, otherwise they are listed as strings and .words.
+pub fn disassemble(v: &[u8], cpux: bool) -> ~[(u16, ~str)] {
+    disassemble_lines(v, cpux).move_iter().map(|l| (l.addr, l.text)).collect()
+}
+
+// a listing row: an instruction, or data when inst is None
+pub struct Line {
+    addr: u16,
+    len: u16,
+    text: ~str,
+    inst: Option<Instruction>
+}
+
+pub fn disassemble_lines(v: &[u8], cpux: bool) -> ~[Line] {
     let mut c = Cpu::new();
+    c.cpux = cpux;
     c.ram.loadimage(v,0);
-    let mut blkstoinclude = HashSet::new();
-    let mut lastblock : u16 = 0;
-    for block in range(0, (c.ram.arr.len()/16) as u16) {
-        //look for interesting blocks
-        let mut sum = 0u;
-        for ix in range(0u16,16u16) { sum += c.ram.arr[16*block + ix] as uint }
-        if sum > 0 { 
-            blkstoinclude.insert(block) ;
-            lastblock = block
-        }
-    }
-    let mut listing : ~[(u16, ~str)] = ~[];
-    while c.regs.arr[0]/16 <= lastblock {
-        //print if interesting
-        c.prepare_next();
-        let blk = c.regs.arr[0]/16;
-        if blkstoinclude.contains(&blk) {listing.push((c.inst.memloc, c.inst.to_string()))}
+    let t = match c.trace() {
+        Some(t) => t,
+        None => return ~[]
+    };
+    let mut listing : ~[Line] = ~[];
+    let mut addr = t.first as uint;
+    while addr < 0x10000 {
+        if !t.blocks.contains(&((addr / 16) as u16)) { addr += 16 - addr % 16; continue }
+        match t.insts.find(&(addr as u16)) {
+            Some(&(inst, len)) => {
+                listing.push(Line { addr: addr as u16, len: len, text: inst.to_string(), inst: Some(inst) });
+                addr += len as uint;
+                continue
+            },
+            None => ()
+        }
+        let mut end = addr;
+        while end < 0x10000 && !t.owned[end] && t.blocks.contains(&((end / 16) as u16)) { end += 1 }
+        c.sweep_gap(addr, end, &mut listing);
+        addr = end;
     }
     listing
 }
-        
+
+impl Cpu {
+    fn trace(&mut self) -> Option<Trace> {
+        let mut blocks = HashSet::new();
+        let mut first = None;
+        let mem = self.ram.bytes();
+        for block in range(0, (mem.len()/16) as u16) {
+            //look for interesting blocks
+            let mut sum = 0u;
+            for ix in range(0u16,16u16) { sum += mem[16*block + ix] as uint }
+            if sum > 0 { 
+                blocks.insert(block) ;
+                if first.is_none() { first = Some(block * 16) }
+            }
+        }
+        let first = match first {
+            Some(a) => a,
+            None => return None
+        };
+        let mut entries = ~[first];
+        for ix in range(0u16, 16) {
+            let vector = self.ram.loadw(0xffe0 + 2 * ix);
+            if blocks.contains(&(vector / 16)) { entries.push(vector) }
+        }
+
+        let mut insts = HashMap::new();
+        let mut owned : ~[bool] = range(0u, 0x10000).map(|_| false).collect();
+        let mut todo = entries.clone();
+        while !todo.is_empty() {
+            let addr = todo.pop().unwrap();
+            if addr % 2 != 0 || owned[addr as uint] || !blocks.contains(&(addr / 16)) { continue }
+            let len = match self.decode_at(addr) {
+                Some(len) => len,
+                None => continue
+            };
+            let span = range(addr as uint, addr as uint + len as uint);
+            if span.clone().any(|a| a > 0xffff || owned[a]) { continue }
+            for a in span { owned[a] = true }
+            insts.insert(addr, (self.inst, len));
+            todo.push_all(self.inst.flow(addr + len));
+        }
+        Some(Trace { insts: insts, owned: owned, blocks: blocks, entries: entries, first: first })
+    }
+
+    // decode the instruction at addr into self.inst and return its length, None if it
+    // is not a valid opcode
+    pub fn decode_at(&mut self, addr: u16) -> Option<u16> {
+        self.regs.store(0, addr);
+        self.prepare_next();
+        if self.inst.namer() == ~"UNKNOWN" { return None }
+        Some(self.regs.arr[0] - addr)
+    }
+
+    // list an unreached gap, as code only if all of it decodes without zero words or strings
+    fn sweep_gap(&mut self, start: uint, end: uint, listing: &mut ~[Line]) {
+        let bytes : ~[u8] = range(start, end).map(|a| self.ram.peek(a as u32)).collect();
+        let has_string = range(0, bytes.len()).any(|ix| string_len(bytes.slice_from(ix)) >= 4);
+        if start % 2 == 0 && end % 2 == 0 && !has_string {
+            let mut lines = ~[];
+            let mut a = start;
+            while a < end && self.ram.loadw(a as u16) != 0 {
+                match self.decode_at(a as u16) {
+                    Some(len) => {
+                        lines.push(Line { addr: a as u16, len: len, text: self.inst.to_string(), inst: Some(self.inst) });
+                        a += len as uint
+                    },
+                    None => break
+                }
+            }
+            if a == end { return listing.push_all_move(lines) }
+        }
+        let mut ix = 0;
+        while ix < bytes.len() {
+            let a = (start + ix) as u16;
+            let n = string_len(bytes.slice_from(ix));
+            if n >= 4 {
+                let mut text = ~".string \"";
+                for &b in bytes.slice(ix, ix + n).iter() {
+                    match b as char {
+                        '"' => text.push_str("\\\""),
+                        '\\' => text.push_str("\\\\"),
+                        '\n' => text.push_str("\\n"),
+                        ch => text.push_char(ch)
+                    }
+                }
+                text.push_char('"');
+                listing.push(Line { addr: a, len: (n + 1) as u16, text: text, inst: None });
+                ix += n + 1;
+            } else if a % 2 != 0 || ix + 1 == bytes.len() {
+                listing.push(Line { addr: a, len: 1, text: format!(".byte 0x{:02x}", bytes[ix]), inst: None });
+                ix += 1;
+            } else {
+                let text = format!(".word 0x{:02x}{:02x}", bytes[ix + 1], bytes[ix]);
+                listing.push(Line { addr: a, len: 2, text: text, inst: None });
+                ix += 2;
+            }
+        }
+    }
+}
+
+// length of the printable NUL-terminated string at the start of bytes, 0 if there is none
+fn string_len(bytes: &[u8]) -> uint {
+    let n = bytes.iter().take_while(|&&b| (b >= 0x20 && b < 0x7f) || b == 0x0a).count();
+    if n < bytes.len() && bytes[n] == 0 { n } else { 0 }
+}
 
 impl fmt::Show for Instruction {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -0,0 +1,105 @@
+// Device profiles: memory map, peripherals and reset behaviour of a target,
+// read from devices.cfg (built in) or a user supplied file.
+
+use conf;
+use conf::Section;
+use mem::{Region, RegionKind, RamKind, FlashKind, InfoKind, SfrKind, PeriphKind};
+use std::io::File;
+
+static BUILTIN : &'static str = include_str!("../devices.cfg");
+
+pub static DEFAULT_DEVICE : &'static str = "microcorruption";
+
+#[deriving(Eq)]
+pub enum Reset {
+    ImageReset,         //start executing at the load address
+    VectorReset         //start at the address in the reset vector (0xfffe)
+}
+
+pub struct Device {
+    name: ~str,
+    regions: ~[Region],
+    peripherals: ~[~str],
+    load: u32,
+    reset: Reset,
+    callgates: bool,
+    cpux: bool,
+    fctl: u32,          //flash controller registers, if the device has one
+    infoseg: u32        //information memory segment size
+}
+
+impl Device {
+    // look up a profile by name, in `path` if given, otherwise in the built in profiles
+    pub fn load(name: &str, path: Option<~str>) -> Result<Device, ~str> {
+        let text = match path {
+            Some(p) => match File::open(&Path::new(p.clone())).read_to_str() {
+                Ok(t) => t,
+                Err(e) => return Err(format!("Could not read {}: {}", p, e))
+            },
+            None => BUILTIN.to_owned()
+        };
+        let sections = try!(conf::parse(text));
+        for sec in sections.iter() {
+            if sec.name.as_slice() == name { return Device::from_section(sec) }
+        }
+        let names : ~[~str] = sections.iter().map(|s| s.name.clone()).collect();
+        Err(format!("Unknown device '{}' (available: {})", name, names.connect(", ")))
+    }
+
+    pub fn default() -> Device {
+        match Device::load(DEFAULT_DEVICE, None) {
+            Ok(d) => d,
+            Err(e) => fail!(e)
+        }
+    }
+
+    fn from_section(sec: &Section) -> Result<Device, ~str> {
+        let mut regions = ~[];
+        for &(key, kind) in [("ram", RamKind), ("flash", FlashKind), ("info", InfoKind),
+                             ("sfr", SfrKind), ("periph", PeriphKind)].iter() {
+            for range in sec.get_all(key).iter() {
+                regions.push(try!(parse_region(sec.name.as_slice(), kind, range.as_slice())));
+            }
+        }
+        let peripherals = match sec.get("peripherals") {
+            Some(p) => p.split(',').map(|s| s.trim().to_owned()).filter(|s| s.len() > 0).collect(),
+            None => ~[]
+        };
+        let reset = match sec.get("reset") {
+            None => ImageReset,
+            Some(r) => match r.as_slice() {
+                "image" => ImageReset,
+                "vector" => VectorReset,
+                _ => return Err(format!("[{}] reset: expected image or vector, got '{}'", sec.name, r))
+            }
+        };
+        Ok(Device {
+            name: sec.name.clone(),
+            regions: regions,
+            peripherals: peripherals,
+            load: try!(sec.get_num("load")).unwrap_or(0x4400),
+            reset: reset,
+            callgates: try!(sec.get_bool("callgates", false)),
+            cpux: try!(sec.get_bool("cpux", false)),
+            fctl: try!(sec.get_num("fctl")).unwrap_or(0x0128),
+            infoseg: try!(sec.get_num("infoseg")).unwrap_or(64)
+        })
+    }
+
+    pub fn has_peripheral(&self, name: &str) -> bool {
+        self.peripherals.iter().any(|p| p.as_slice() == name)
+    }
+}
+
+fn parse_region(device: &str, kind: RegionKind, s: &str) -> Result<Region, ~str> {
+    let parts : ~[&str] = s.split('-').collect();
+    if parts.len() == 2 {
+        match (conf::parse_num(parts[0]), conf::parse_num(parts[1])) {
+            (Some(start), Some(end)) if start <= end && end <= 0xfffff => {
+                return Ok(Region { kind: kind, start: start, end: end })
+            },
+            _ => ()
+        }
+    }
+    Err(format!("[{}] invalid address range '{}'", device, s))
+}
@@ -0,0 +1,151 @@
+// Disassembly output for -d: the plain listing, an objdump-like layout with raw bytes,
+// or JSON for other tools.
+
+use analysis::Analysis;
+use cpu;
+use cpu::{Cpu, Line, Instruction, AddressingMode, Direct, Indexed, Indirect, IndirectInc, Absolute, Const};
+use device::Device;
+use std::ascii::StrAsciiExt;
+
+pub static FORMATS : &'static str = "text, objdump, json";
+
+pub fn print(image: &[u8], dev: &Device, format: &str) -> Result<(), ~str> {
+    let cpu = Cpu::init(image, dev);
+    let bytes = cpu.ram.bytes();
+    let mem = bytes.as_slice();
+    let lines = cpu::disassemble_lines(mem, dev.cpux);
+    let symbols = Analysis::run(mem, dev.cpux);
+    match format {
+        "text" => text(lines, &symbols),
+        "objdump" => objdump(mem, lines, &symbols),
+        "json" => json(mem, lines, &symbols),
+        _ => return Err(format!("Unknown format {}, expected one of {}", format, FORMATS))
+    }
+    Ok(())
+}
+
+fn text(lines: &[Line], symbols: &Analysis) {
+    for l in lines.iter() {
+        match symbols.starting_at(l.addr) {
+            Some(f) => println!("\n{:04x} <{}>:", l.addr, f.name),
+            None => ()
+        }
+        match symbols.comments.find(&l.addr) {
+            Some(c) => println!("{:04x}: {} {}", l.addr, l.text, *c),
+            None => println!("{:04x}: {}", l.addr, l.text)
+        }
+    }
+}
+
+fn bytes_of<'a>(mem: &'a [u8], l: &Line) -> &'a [u8] {
+    let end = ::std::cmp::min(l.addr as uint + l.len as uint, mem.len());
+    mem.slice(l.addr as uint, end)
+}
+
+fn hex(bytes: &[u8], sep: &str) -> ~str {
+    let parts : ~[~str] = bytes.iter().map(|b| format!("{:02x}", *b)).collect();
+    parts.connect(sep)
+}
+
+// objdump -d style: label headers, then address, raw bytes (8 per row) and the
+// instruction in lower case
+fn objdump(mem: &[u8], lines: &[Line], symbols: &Analysis) {
+    println!("Disassembly of section .text:");
+    for l in lines.iter() {
+        match symbols.starting_at(l.addr) {
+            Some(f) => println!("\n{:08x} <{}>:", l.addr, f.name),
+            None => ()
+        }
+        let text = match l.inst {
+            Some(inst) => {
+                let ops = inst.operand_text();
+                format!("{}\t{}", inst.mnemonic().to_ascii_lower(), ops.connect(", "))
+            },
+            None => l.text.clone()
+        };
+        let comment = symbols.comments.find(&l.addr).map(|c| format!("\t;{}", *c)).unwrap_or(~"");
+        let bytes = bytes_of(mem, l);
+        for (ix, chunk) in bytes.chunks(8).enumerate() {
+            let addr = l.addr as uint + 8 * ix;
+            if ix == 0 {
+                println!("{:8x}:\t{:24s}\t{}{}", addr, hex(chunk, " "), text, comment);
+            } else {
+                println!("{:8x}:\t{:24s}", addr, hex(chunk, " "));
+            }
+        }
+    }
+}
+
+fn escape(s: &str) -> ~str {
+    let mut out = ~"";
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => out.push_str(format!("\\\\u{:04x}", c as u32)),
+            c => out.push_char(c)
+        }
+    }
+    out
+}
+
+fn mode_name(mode: AddressingMode, reg: u8) -> &'static str {
+    match mode {
+        Direct => "register",
+        Indexed(_) if reg == 0 => "symbolic",
+        Indexed(_) => "indexed",
+        Indirect => "indirect",
+        IndirectInc => "autoincrement",
+        Absolute(_) => "absolute",
+        Const(_) if reg == 0 => "immediate",
+        Const(_) => "constant"
+    }
+}
+
+fn width(inst: &Instruction) -> &'static str {
+    if inst.bw { "byte" } else if inst.aw { "address" } else { "word" }
+}
+
+fn json_inst(l: &Line, inst: &Instruction) -> ~str {
+    let texts = inst.operand_text();
+    let modes = inst.operands();
+    let mut ops : ~[~str] = ~[];
+    for (ix, t) in texts.iter().enumerate() {
+        //operand_text and operands agree except for jumps, RPxM and PUSHM/POPM counts
+        let mode = if inst.is_jump() || ix >= modes.len() || texts.len() != modes.len() {
+            ~"null"
+        } else {
+            let (m, r, _) = modes[ix];
+            format!("\"{}\"", mode_name(m, r))
+        };
+        ops.push(format!("\\{\"text\": \"{}\", \"mode\": {}\\}", escape(t.as_slice()), mode));
+    }
+    let next = l.addr + l.len;
+    let target = match inst.flow(next).move_iter().find(|&t| t != next) {
+        Some(t) => format!("{}", t),
+        None => ~"null"
+    };
+    format!("\"mnemonic\": \"{}\", \"width\": \"{}\", \"operands\": [{}], \"target\": {}",
+            escape(inst.mnemonic()), width(inst), ops.connect(", "), target)
+}
+
+// one object per row: address and bytes, then either the decoded instruction or the
+// data directive
+fn json(mem: &[u8], lines: &[Line], symbols: &Analysis) {
+    println!("[");
+    for (ix, l) in lines.iter().enumerate() {
+        let body = match l.inst {
+            Some(ref inst) => json_inst(l, inst),
+            None => format!("\"data\": \"{}\"", escape(l.text.as_slice()))
+        };
+        let label = match symbols.label(l.addr) {
+            ref s if s.len() > 0 => format!("\"{}\"", escape(s.as_slice())),
+            _ => ~"null"
+        };
+        let sep = if ix + 1 < lines.len() { "," } else { "" };
+        println!("  \\{\"address\": {}, \"bytes\": \"{}\", \"label\": {}, {}\\}{}",
+                 l.addr, hex(bytes_of(mem, l), ""), label, body, sep);
+    }
+    println!("]");
+}
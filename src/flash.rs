@@ -0,0 +1,139 @@
+// Flash memory controller (FCTL1-FCTL3)
+//
+// Flash can only be changed through the controller: a dummy write with ERASE set
+// wipes a segment to 0xff, and a write with WRT set can only clear bits.
+
+use mem::{Ram, RegionKind, FlashKind, InfoKind, FlashLocked, FlashKey, FlashSegA};
+
+//FCTL1
+static ERASE : u16 = 1 << 1;
+static MERAS : u16 = 1 << 2;
+static WRT : u16 = 1 << 6;
+//FCTL3
+static KEYV : u16 = 1 << 1;
+static ACCVIFG : u16 = 1 << 2;
+static LOCK : u16 = 1 << 4;
+static LOCKA : u16 = 1 << 6;
+
+static FWKEY : u8 = 0xa5;       //must be in the upper byte of every register write
+static FRKEY : u8 = 0x96;       //reads back in the upper byte
+static MAINSEG : u32 = 512;
+
+pub struct FlashCtl {
+    base: u32,                  //address of FCTL1, FCTL3 is at base + 4
+    infoseg: u32,               //information memory segment size
+    regs: [u16, ..3],
+    pending: Option<u8>         //low byte of a register write in progress
+}
+
+impl FlashCtl {
+    pub fn new(base: u32, infoseg: u32) -> FlashCtl {
+        FlashCtl { base: base, infoseg: infoseg, regs: [0, 0, LOCK | LOCKA], pending: None }
+    }
+
+    pub fn is_register(&self, addr: u32) -> bool {
+        addr >= self.base && addr < self.base + 6
+    }
+}
+
+// make the register values visible to normal loads
+pub fn mirror(ram: &mut Ram) {
+    let ctl = ram.flash.unwrap();
+    for ix in range(0u, 3) {
+        let addr = ctl.base + 2 * ix as u32;
+        ram.poke(addr, ctl.regs[ix] as u8);
+        ram.poke(addr + 1, FRKEY);
+    }
+}
+
+// registers are word wide and take effect once the upper byte (the key) arrives
+pub fn write_reg(ram: &mut Ram, addr: u32, val: u8) -> bool {
+    let mut ctl = ram.flash.unwrap();
+    let off = addr - ctl.base;
+    if off % 2 == 0 {
+        ctl.pending = Some(val);
+        ram.flash = Some(ctl);
+        return true
+    }
+    let low = ctl.pending.unwrap_or(ram.peek(addr - 1)) as u16;
+    ctl.pending = None;
+    let ok = if val != FWKEY {
+        ctl.regs[2] |= KEYV;
+        ram.fault = Some(FlashKey(addr - 1, val));
+        false
+    } else {
+        let ix = (off / 2) as uint;
+        ctl.regs[ix] = match ix {
+            2 => {
+                //LOCKA toggles when written with 1, the rest are plain bits
+                let mut r = (ctl.regs[2] & LOCKA) | (low & (LOCK | ACCVIFG | KEYV));
+                if low & LOCKA != 0 { r ^= LOCKA }
+                r
+            },
+            _ => low
+        };
+        true
+    };
+    ram.flash = Some(ctl);
+    mirror(ram);
+    ok
+}
+
+// a store into a flash or information memory region
+pub fn write_flash(ram: &mut Ram, addr: u32, kind: RegionKind, val: u8) -> bool {
+    let mut ctl = ram.flash.unwrap();
+    let (fctl1, fctl3) = (ctl.regs[0], ctl.regs[2]);
+    let ok = if fctl3 & LOCK != 0 || fctl1 & (ERASE | MERAS | WRT) == 0 {
+        ctl.regs[2] |= ACCVIFG;
+        ram.fault = Some(FlashLocked(addr));
+        false
+    } else if kind == InfoKind && fctl3 & LOCKA != 0 && in_seg_a(ram, &ctl, addr) {
+        ctl.regs[2] |= ACCVIFG;
+        ram.fault = Some(FlashSegA(addr));
+        false
+    } else if fctl1 & (ERASE | MERAS) != 0 {
+        erase(ram, &ctl, addr, kind, fctl1);
+        ctl.regs[0] &= !(ERASE | MERAS);
+        true
+    } else {
+        let old = ram.peek(addr);
+        ram.poke(addr, old & val);
+        true
+    };
+    ram.flash = Some(ctl);
+    mirror(ram);
+    ok
+}
+
+// segment A is the last segment of information memory
+fn in_seg_a(ram: &Ram, ctl: &FlashCtl, addr: u32) -> bool {
+    match ram.region(addr) {
+        Some(r) => addr > r.end - ctl.infoseg,
+        None => false
+    }
+}
+
+fn fill(ram: &mut Ram, start: u32, end: u32) {
+    for a in range(start, end + 1) { ram.poke(a, 0xff) }
+}
+
+fn erase(ram: &mut Ram, ctl: &FlashCtl, addr: u32, kind: RegionKind, fctl1: u16) {
+    if fctl1 & MERAS == 0 {
+        let seg = if kind == InfoKind { ctl.infoseg } else { MAINSEG };
+        let start = addr & !(seg - 1);
+        fill(ram, start, start + seg - 1);
+        return
+    }
+    //mass erase: all of main memory, plus information memory with ERASE as well
+    let lock_a = ctl.regs[2] & LOCKA != 0;
+    for r in ram.map.clone().iter() {
+        match r.kind {
+            FlashKind => fill(ram, r.start, r.end),
+            InfoKind if fctl1 & ERASE != 0 => {
+                let end = if lock_a { r.end - ctl.infoseg } else { r.end };
+                fill(ram, r.start, end)
+            },
+            _ => ()
+        }
+    }
+}
@@ -0,0 +1,265 @@
+// Coverage-guided input fuzzer
+//
+// The program runs once from reset up to its first getsn and the CPU is snapshotted
+// there. Every exec restarts from the snapshot on one of the pool's workers, answers
+// each getsn with the same mutated input and runs to the end, counting control-flow
+// edges. Inputs that hit a new edge, or hit one a new number of times (in AFL's
+// buckets), join the corpus. Unlocks and faults, which include DEP violations and
+// corrupted return addresses since the smash check is always on, are saved as they
+// would be typed at the prompt.
+
+use analysis::Analysis;
+use collections::{HashMap, HashSet};
+use cpu::{Cpu, GetInput, Normal};
+use device::Device;
+use pool::{Pool, Job};
+use rand::{task_rng, Rng};
+use runner;
+use std::io;
+use std::io::File;
+use std::io::fs;
+use std::os;
+use super::str2bytes;
+use symex::show_input;
+
+pub static DEFAULT_EXECS : uint = 100000;
+pub static DEFAULT_CHARSET : &'static str = "abcdefghijklmnopqrstuvwxyz0123456789";
+pub static DEFAULT_LENGTH : uint = 4;
+static EXECSTEPS : uint = 100000;       //instructions per exec before it counts as a hang
+static MAXINPUTS : uint = 8;            //getsn calls answered per exec
+static MAXLEN : uint = 256;
+static REPORT : uint = 5000;            //execs between status lines
+static INTERESTING : [u8, ..8] = [0x00, 0x01, 0x10, 0x41, 0x7f, 0x80, 0xfe, 0xff];
+
+// AFL's hit count classes, one bit each
+fn bucket(n: u8) -> u8 {
+    match n {
+        0 => 0,
+        1 => 1,
+        2 => 2,
+        3 => 4,
+        4..7 => 8,
+        8..15 => 16,
+        16..31 => 32,
+        32..127 => 64,
+        _ => 128
+    }
+}
+
+// merge a run's edge counts into the classes seen so far, true if any was new
+fn new_coverage(seen: &mut [u8], edges: &[(u16, u8)]) -> bool {
+    let mut new = false;
+    for &(ix, n) in edges.iter() {
+        let b = bucket(n);
+        if seen[ix] & b == 0 {
+            seen[ix] |= b;
+            new = true
+        }
+    }
+    new
+}
+
+// run from reset to the first getsn
+fn snapshot(image: &[u8], dev: &Device, hsm_password: Option<~[u8]>) -> Result<Cpu, ~str> {
+    let mut cpu = Cpu::init(image, dev);
+    cpu.hsm_password = hsm_password;
+    cpu.enable_smash_check();
+    cpu.track_edges();
+    for _ in range(0, runner::STEPLIMIT) {
+        let waiting = match cpu.status {
+            GetInput(ref bytes) => bytes.len() == 0,
+            Normal => false,
+            _ => return Err(~"the program ended before asking for input")
+        };
+        if waiting {
+            cpu.track_edges();
+            cpu.buf = ~"";
+            cpu.share();
+            return Ok(cpu)
+        }
+        cpu.step();
+    }
+    Err(format!("no input requested after {} steps", runner::STEPLIMIT))
+}
+
+fn mutate<R: Rng>(rng: &mut R, corpus: &[~[u8]], words: &[u16], input: &[u8]) -> ~[u8] {
+    let mut out = input.to_owned();
+    for _ in range(0, rng.gen_range(1u, 5)) {
+        let len = out.len();
+        let ix = rng.gen_range(0u, len + 1);
+        match rng.gen_range(0u, 8) {
+            0 if ix < len => out[ix] ^= 1 << rng.gen_range(0u8, 8),
+            1 if ix < len => out[ix] = INTERESTING[rng.gen_range(0u, INTERESTING.len())],
+            2 if ix < len => out[ix] = rng.gen(),
+            3 if ix < len => {
+                //delete a run
+                let n = rng.gen_range(1u, len - ix + 1);
+                let mut v = out.slice_to(ix).to_owned();
+                v.push_all(out.slice_from(ix + n));
+                out = v
+            },
+            4 => {
+                //insert a run of one byte, to grow past buffers
+                let b = if rng.gen() { 0x41 } else { rng.gen() };
+                let n = rng.gen_range(1u, 17);
+                let mut v = out.slice_to(ix).to_owned();
+                v.grow(n, &b);
+                v.push_all(out.slice_from(ix));
+                out = v
+            },
+            5 if words.len() > 0 => {
+                //a function address, little-endian, e.g. for a return address
+                let w = words[rng.gen_range(0u, words.len())];
+                let bytes = [(w & 0xff) as u8, (w >> 8) as u8];
+                for (k, &b) in bytes.iter().enumerate() {
+                    if ix + k < out.len() { out[ix + k] = b } else { out.push(b) }
+                }
+            },
+            6 if ix < len => {
+                //repeat a chunk
+                let n = rng.gen_range(1u, len - ix + 1);
+                let chunk = out.slice(ix, ix + n).to_owned();
+                let mut v = out.slice_to(ix + n).to_owned();
+                v.push_all(chunk);
+                v.push_all(out.slice_from(ix + n));
+                out = v
+            },
+            _ => {
+                //splice with another corpus entry
+                let other = &corpus[rng.gen_range(0u, corpus.len())];
+                let cut = rng.gen_range(0u, other.len() + 1);
+                let mut v = out.slice_to(ix).to_owned();
+                v.push_all(other.slice_from(cut));
+                out = v
+            }
+        }
+        out.truncate(MAXLEN);
+    }
+    out
+}
+
+fn save(dir: &Path, kind: &str, n: uint, input: &[u8]) -> Result<Path, ~str> {
+    let path = dir.join(format!("{}-{}.txt", kind, n));
+    match File::create(&path).write_str(show_input(input) + "\n") {
+        Ok(()) => Ok(path),
+        Err(e) => Err(format!("Could not write {}: {}", path.display(), e))
+    }
+}
+
+// every getsn in a run gets the bytes the prompt would deliver for the saved form
+fn job(id: uint, input: &[u8]) -> Job {
+    let typed = str2bytes(show_input(input));
+    Job { id: id, inputs: range(0, MAXINPUTS).map(|_| typed.clone()).collect() }
+}
+
+// uctf fuzz [--execs N] [-j N] [-o DIR] IMAGE
+pub fn run(image: &[u8], dev: &Device, hsm_password: Option<~[u8]>, outdir: Option<~str>, execs: uint,
+           workers: uint) {
+    let base = match snapshot(image, dev, hsm_password) {
+        Ok(c) => c,
+        Err(e) => { println!("{}", e); os::set_exit_status(1); return }
+    };
+    let dir = Path::new(outdir.unwrap_or(~"fuzz-out"));
+    match fs::mkdir_recursive(&dir, io::UserRWX) {
+        Ok(()) => (),
+        Err(e) => { println!("Could not create {}: {}", dir.display(), e); os::set_exit_status(1); return }
+    }
+    let words : ~[u16] = Analysis::run(base.ram.bytes(), dev.cpux).functions.iter().map(|f| f.start).collect();
+    let mut corpus = ~[bytes!("AAAAAAAA").to_owned(), bytes!("password").to_owned(), ~[0u8, 0]];
+    let seeds = corpus.len();
+    let mut seen = range(0u, 0x10000).map(|_| 0u8).collect::<~[u8]>();
+    let mut outcomes = HashSet::new();      //descriptions of the outcomes already saved
+    let mut running = HashMap::new();       //inputs in flight, by job id
+    let (mut unlocks, mut crashes) = (0u, 0u);
+    let mut rng = task_rng();
+    let mut pool = Pool::new(&base, workers, EXECSTEPS);
+    let mut submitted = 0u;
+    while pool.stats.execs < execs {
+        while submitted < execs && pool.has_room() {
+            let input = if submitted < seeds {
+                corpus[submitted].clone()
+            } else {
+                let parent = corpus[rng.gen_range(0u, corpus.len())].clone();
+                mutate(&mut rng, corpus, words, parent)
+            };
+            pool.submit(job(submitted, input));
+            running.insert(submitted, input);
+            submitted += 1;
+        }
+        let done = pool.recv();
+        let input = running.pop(&done.job.id).unwrap();
+        if pool.stats.execs % REPORT == 0 {
+            println!("{}, corpus {}", pool.stats.describe(), corpus.len());
+        }
+        if new_coverage(seen.as_mut_slice(), done.edges.as_slice()) && done.job.id >= seeds { corpus.push(input.clone()) }
+        let kind = match done.outcome {
+            runner::Unlocked => "unlock",
+            runner::Fault(_) => "crash",
+            _ => continue
+        };
+        let what = done.outcome.describe();
+        if !outcomes.insert(what.clone()) { continue }
+        let count = if kind == "unlock" { unlocks += 1; unlocks } else { crashes += 1; crashes };
+        match save(&dir, kind, count, input) {
+            Ok(p) => println!("{}: {} ({})", p.display(), what, show_input(input)),
+            Err(e) => { println!("{}", e); os::set_exit_status(1); break }
+        }
+    }
+    pool.finish();
+    println!("{}, corpus {}, {} unlocks and {} crashes saved", pool.stats.describe(), corpus.len(), unlocks, crashes);
+}
+
+// the next string over an alphabet of n, shortest first; false past maxlen
+fn advance(digits: &mut ~[uint], n: uint, maxlen: uint) -> bool {
+    for d in digits.mut_iter() {
+        *d += 1;
+        if *d < n { return true }
+        *d = 0
+    }
+    if digits.len() == maxlen { return false }
+    digits.push(0);
+    true
+}
+
+// uctf brute [--charset C] [--length N] [-j N] IMAGE: try every string over the charset,
+// shortest first, until one unlocks the door
+pub fn brute(image: &[u8], dev: &Device, hsm_password: Option<~[u8]>, charset: &str, maxlen: uint,
+             workers: uint) {
+    let base = match snapshot(image, dev, hsm_password) {
+        Ok(c) => c,
+        Err(e) => { println!("{}", e); os::set_exit_status(1); return }
+    };
+    let chars = charset.as_bytes();
+    if chars.len() == 0 { println!("Empty charset"); os::set_exit_status(1); return }
+    let mut pool = Pool::new(&base, workers, EXECSTEPS);
+    let mut running = HashMap::new();
+    let mut digits = ~[];
+    let mut more = true;
+    let mut submitted = 0u;
+    loop {
+        while more && pool.has_room() {
+            let input : ~[u8] = digits.iter().map(|&d| chars[d]).collect();
+            pool.submit(job(submitted, input));
+            running.insert(submitted, input);
+            submitted += 1;
+            more = advance(&mut digits, chars.len(), maxlen);
+        }
+        if pool.in_flight() == 0 { break }
+        let done = pool.recv();
+        let input = running.pop(&done.job.id).unwrap();
+        if pool.stats.execs % REPORT == 0 { println!("{}", pool.stats.describe()) }
+        match done.outcome {
+            runner::Unlocked => {
+                pool.finish();
+                println!("Found: {}", show_input(input));
+                println!("{}", pool.stats.describe());
+                return
+            },
+            _ => ()
+        }
+    }
+    pool.finish();
+    println!("No input of up to {} characters unlocks the door", maxlen);
+    println!("{}", pool.stats.describe());
+    os::set_exit_status(1)
+}
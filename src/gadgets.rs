@@ -0,0 +1,179 @@
+// ROP gadgets: short instruction sequences ending in RET or an indirect BR
+//
+// Every even address of the loaded image is decoded as a possible gadget end. For each
+// end the bytes before it are decoded from every even start up to MAXINSTS
+// instructions back, keeping the starts that run straight into the end without
+// another branch. Gadgets with the same instructions are listed once, with all their
+// addresses.
+
+use collections::HashMap;
+use cpu::{Cpu, Instruction, Direct, IndirectInc, Const};
+use device::Device;
+use std::os;
+
+static MAXINSTS : uint = 5;             //including the RET or BR
+static MAXLEN : u16 = 6;                //bytes in the longest instruction
+
+pub struct Effects {
+    pops: ~[u8],                //registers loaded with MOV @SP+, in order
+    writes: ~[u8],              //registers written otherwise
+    sp: Option<i32>,            //net change to SP, None if it is set from elsewhere
+    writes_mem: bool
+}
+
+pub struct Gadget {
+    addrs: ~[u16],
+    insts: ~[Instruction],
+    text: ~str,
+    effects: Effects
+}
+
+pub struct Filter {
+    pops: Option<u8>,
+    writes_mem: bool
+}
+
+impl Filter {
+    fn matches(&self, g: &Gadget) -> bool {
+        let pops = match self.pops {
+            Some(r) => g.effects.pops.contains(&r),
+            None => true
+        };
+        pops && (!self.writes_mem || g.effects.writes_mem)
+    }
+}
+
+// RET (MOV @SP+, PC), or BR through a register or memory
+fn is_end(inst: &Instruction) -> bool {
+    inst.namer() == ~"MOV" && inst.destreg == 0 && match (inst.destmode, inst.srcmode) {
+        (Direct, Const(_)) => false,
+        (Direct, _) => true,
+        _ => false
+    }
+}
+
+fn effects(insts: &[Instruction]) -> Effects {
+    let mut e = Effects { pops: ~[], writes: ~[], sp: Some(0), writes_mem: false };
+    for inst in insts.iter() {
+        let name = inst.namer();
+        let is_pop = name == ~"MOV" && inst.srcreg == 1 && match inst.srcmode { IndirectInc => true, _ => false };
+        for &(mode, reg, written) in inst.operands().iter() {
+            match mode {
+                IndirectInc if reg == 1 => e.sp = e.sp.map(|s| s + 2),
+                _ => ()
+            }
+            if !written { continue }
+            match mode {
+                Direct if reg == 1 => {
+                    e.sp = match (name.as_slice(), inst.srcmode) {
+                        ("ADD", Const(n)) => e.sp.map(|s| s + n as i16 as i32),
+                        ("SUB", Const(n)) => e.sp.map(|s| s - n as i16 as i32),
+                        _ => None
+                    }
+                },
+                Direct if reg == 0 => (),
+                Direct if is_pop => e.pops.push(reg),
+                Direct => if !e.writes.contains(&reg) { e.writes.push(reg) },
+                Const(_) => (),
+                _ => e.writes_mem = true
+            }
+        }
+        if name == ~"PUSH" {
+            e.sp = e.sp.map(|s| s - 2);
+            e.writes_mem = true
+        }
+    }
+    e
+}
+
+fn regs(rs: &[u8]) -> ~str {
+    let names : ~[~str] = rs.iter().map(|r| format!("r{}", *r)).collect();
+    names.connect(" ")
+}
+
+impl Effects {
+    pub fn describe(&self) -> ~str {
+        let mut parts = ~[];
+        if self.pops.len() > 0 { parts.push(format!("pops {}", regs(self.pops))) }
+        if self.writes.len() > 0 { parts.push(format!("sets {}", regs(self.writes))) }
+        parts.push(match self.sp {
+            Some(n) if n >= 0 => format!("sp +{}", n),
+            Some(n) => format!("sp {}", n),
+            None => ~"sp ?"
+        });
+        if self.writes_mem { parts.push(~"writes memory") }
+        parts.connect(", ")
+    }
+}
+
+fn text(insts: &[Instruction]) -> ~str {
+    let parts : ~[~str] = insts.iter().map(|i| format!("{} {}", i.mnemonic(), i.operand_text().connect(", ")).trim().to_owned()).collect();
+    parts.connect("; ")
+}
+
+// the instructions from start, if they run straight into end
+fn run_to(cpu: &mut Cpu, start: u16, end: u16) -> Option<~[Instruction]> {
+    let mut out = ~[];
+    let mut addr = start;
+    while addr < end && out.len() < MAXINSTS - 1 {
+        let len = match cpu.decode_at(addr) {
+            Some(len) => len,
+            None => return None
+        };
+        let inst = cpu.inst;
+        if inst.is_jump() || inst.is_call() || inst.flow(addr + len) != ~[addr + len] { return None }
+        out.push(inst);
+        addr += len;
+    }
+    if addr == end { Some(out) } else { None }
+}
+
+pub fn find(image: &[u8], dev: &Device) -> ~[Gadget] {
+    let mut cpu = Cpu::init(image, dev);
+    let lo = dev.load as uint & !1;
+    let hi = ::std::cmp::min(dev.load as uint + image.len(), 0x10000);
+    let mut found : ~[Gadget] = ~[];
+    let mut index = HashMap::new();
+    for end in range(lo, hi).filter(|a| a % 2 == 0) {
+        let end = end as u16;
+        match cpu.decode_at(end) {
+            Some(len) if end as uint + len as uint <= hi && is_end(&cpu.inst) => (),
+            _ => continue
+        }
+        let last = cpu.inst;
+        let back = MAXLEN * (MAXINSTS as u16 - 1);
+        let first = if end as uint >= lo + back as uint { end - back } else { lo as u16 };
+        for start in range(first, end + 1).filter(|a| a % 2 == 0) {
+            let mut insts = match run_to(&mut cpu, start, end) {
+                Some(i) => i,
+                None => continue
+            };
+            insts.push(last);
+            let t = text(insts);
+            match index.find(&t) {
+                Some(&ix) => { found[ix].addrs.push(start); continue },
+                None => ()
+            }
+            index.insert(t.clone(), found.len());
+            found.push(Gadget { addrs: ~[start], effects: effects(insts), insts: insts, text: t });
+        }
+    }
+    found.sort_by(|a, b| a.addrs[0].cmp(&b.addrs[0]));
+    found
+}
+
+// uctf gadgets [--pops REG] [--writes-mem] IMAGE
+pub fn run(image: &[u8], dev: &Device, filter: Filter) {
+    if image.len() == 0 { println!("Empty image"); os::set_exit_status(1); return }
+    let gadgets = find(image, dev);
+    let mut shown = 0u;
+    for g in gadgets.iter().filter(|g| filter.matches(*g)) {
+        println!("{:04x}: {:40s} ; {}", g.addrs[0], g.text, g.effects.describe());
+        if g.addrs.len() > 1 {
+            let more : ~[~str] = g.addrs.slice_from(1).iter().map(|a| format!("{:04x}", *a)).collect();
+            println!("      also at {}", more.connect(" "));
+        }
+        shown += 1;
+    }
+    println!("{} gadgets", shown);
+}
@@ -2,7 +2,15 @@
 use ncurses::*;
 use mem;
 use cpu;
+use analysis::Analysis;
+use stack;
+use heap::HeapView;
+use command;
+use command::History;
+use session::Edits;
+use taint::Taint;
 use std;
+use std::ascii::StrAsciiExt;
 
 static RAMHEIGHT : i32 = 60; static RAMWIDTH : i32 = 70; static RAMX : i32 = 01; static RAMY : i32 = 01;
 static REGHEIGHT : i32 = 07; static REGWIDTH : i32 = 40; static REGX : i32 = RAMWIDTH + RAMX + 1; static REGY : i32 = 01;
@@ -11,6 +19,19 @@ static DISHEIGHT : i32 = RAMHEIGHT + RAMY - DISY; static DISWIDTH : i32 = 40; st
 
 static ASMHEIGHT : i32 = 07; static ASMWIDTH : i32 = 70; static ASMX : i32 = RAMWIDTH + RAMX + 1; static ASMY : i32 = 21;
 
+// what the left hand pane shows
+#[deriving(Eq)]
+pub enum Pane {
+    RamPane,
+    PagePane,           //DEP page map
+    StackPane,          //backtrace and stack
+    HeapPane            //malloc chunks
+}
+
+static TAINTCOL : i16 = 7;        //colour pair of tainted memory and registers
+static EDITCOL : i16 = 8;         //memory and registers changed by hand
+static CURSORCOL : i16 = 9;       //the byte being edited
+
 pub struct Gui {
     ramwin : WINDOW,
     regwin : WINDOW,
@@ -18,7 +39,14 @@ pub struct Gui {
     diswin : WINDOW,
     dbgwin : WINDOW,
     listing: ~[(u16, ~str)],
-    reglit: [bool,..16]
+    symbols: Analysis,
+    reglit: [bool,..16],
+    pane: Pane,
+    heap: Option<HeapView>,
+    edits: Edits,
+    cursor: Option<u16>,        //byte being edited in the RAM pane
+    regcursor: Option<uint>,    //register (0-15) or flag (16-19) being edited
+    regpc: Option<u16>          //pc typed in the register editor, set on enter
 }
 
 impl Gui {
@@ -36,6 +64,9 @@ impl Gui {
         init_pair(4, 4, COLOR_WHITE);
         init_pair(5, 5, COLOR_WHITE);
         init_pair(6, 6, COLOR_WHITE);
+        init_pair(TAINTCOL, COLOR_WHITE, COLOR_RED);
+        init_pair(EDITCOL, COLOR_WHITE, COLOR_BLUE);
+        init_pair(CURSORCOL, COLOR_BLACK, COLOR_YELLOW);
 
         let ramwin = newwin(RAMHEIGHT, RAMWIDTH, RAMY, RAMX);
         let regwin = newwin(REGHEIGHT, REGWIDTH, REGY, REGX);
@@ -48,6 +79,8 @@ impl Gui {
         box_(asmwin, 0, 0);
         box_(dbgwin, 0, 0);
         box_(diswin, 0, 0);
+        keypad(dbgwin, true);
+        keypad(stdscr, true);
 
         /*
         mvwprintw(ramwin,0, 10, "RAM");
@@ -65,26 +98,52 @@ impl Gui {
             dbgwin: dbgwin,
             diswin: diswin,
             listing: ~[],
-            reglit: [false,..16]
+            symbols: Analysis::new(),
+            reglit: [false,..16],
+            pane: RamPane,
+            heap: None,
+            edits: Edits::new(),
+            cursor: None,
+            regcursor: None,
+            regpc: None
         }
     }
     
+    // the listing with a <name>: row ahead of every function, callee names appended and
+    // the first references to each address noted
+    fn labelled_listing(&self) -> ~[(Option<u16>, ~str)] {
+        let mut rows = ~[];
+        for &(rownum, ref entry) in self.listing.iter() {
+            let mut note = self.symbols.xref_note(rownum).unwrap_or(~"");
+            match self.symbols.starting_at(rownum) {
+                Some(f) => { rows.push((None, format!("{}: {}", f.name, note))); note = ~"" },
+                None => ()
+            }
+            let entry = match self.symbols.comments.find(&rownum) {
+                Some(c) => format!("{} {} {}", *entry, *c, note),
+                None => format!("{} {}", *entry, note)
+            };
+            rows.push((Some(rownum), format!("{:04x}: {:30s}", rownum, entry)));
+        }
+        rows
+    }
+
     fn draw_disasm(&self, pc: u16) {
-        let lln = self.listing.len() as u16;
+        let rows = self.labelled_listing();
+        let lln = rows.len() as u16;
         let blocksize = (DISHEIGHT-2) as u16;
-        let nblocks = lln/blocksize;
         let mut pcblock = 0u16;
-        for (ix, (rownum, _)) in self.listing.clone().move_iter().enumerate() {
-            if rownum == pc { pcblock = ix as u16 / blocksize; break } }
-        mvwprintw(self.diswin,0, 10, "   Instuctions   ");
+        for (ix, &(rownum, _)) in rows.iter().enumerate() {
+            if rownum == Some(pc) { pcblock = ix as u16 / blocksize; break } }
+        mvwprintw(self.diswin,0, 10, format!("   Instuctions {:20s}", self.symbols.label(pc)));
         for ix in range(0, blocksize) {
             let ind = pcblock * blocksize + ix;
             if ind < lln {
-                let (rownum, entry) = self.listing[ind].clone();
-                if rownum == pc {
-                    colmvprintw(self.diswin, (ix + 1) as i32, 1, 1, format!("{:04x}: {:30s}", rownum, entry));
+                let (rownum, ref line) = rows[ind];
+                if rownum == Some(pc) {
+                    colmvprintw(self.diswin, (ix + 1) as i32, 1, 1, line.clone());
                 } else {
-                    mvwprintw(self.diswin, (ix + 1) as i32, 1, format!("{:04x}: {:30s}", rownum, entry));
+                    mvwprintw(self.diswin, (ix + 1) as i32, 1, format!("{:36s}", *line));
                 }
             } else {
                 mvwprintw(self.diswin, (ix + 1) as i32, 1, "                    "); 
@@ -93,16 +152,19 @@ impl Gui {
         wrefresh(self.diswin);
     }
 
-    fn draw_ram(&mut self, r: mem::Ram, regs: mem::Regs, pc: u16) {
+    fn draw_ram(&mut self, r: &mem::Ram, regs: mem::Regs, pc: u16, taint: &Option<~Taint>) {
         mvwprintw(self.ramwin,0, 10, "   RAM   ");
         self.reglit = [false,..16];
         let mut rowct = 1;
         let mut printlast = false;
-        'rows: for row in std::iter::range(0, r.arr.len()/16) {
+        let arr = r.bytes();
+        let (cursor, edits) = (self.cursor, &self.edits);
+        'rows: for row in std::iter::range(0, arr.len()/16) {
             if rowct > RAMHEIGHT - 2 { break }
             let mut zero = true;
             for col in range(0, 16u) {
-                if r.arr[row * 16 + col] != 0 { zero = false } 
+                let a = (row * 16 + col) as u16;
+                if arr[row * 16 + col] != 0 || edits.mem.contains(&a) || cursor == Some(a) { zero = false }
             }
             match (printlast, zero) {
                 (true,true) => { 
@@ -122,33 +184,157 @@ impl Gui {
             'cols : for col in range(0u, 16u) { 
                 if col % 2 == 0 {
                     let celln = row * 16 + col;
+                    let marked = |a: uint| if cursor == Some(a as u16) { Some(CURSORCOL) }
+                                           else if edits.mem.contains(&(a as u16)) { Some(EDITCOL) } else { None };
+                    if marked(celln).is_some() || marked(celln + 1).is_some() {
+                        for &a in [celln, celln + 1].iter() {
+                            match marked(a) {
+                                Some(pair) => colprint(self.ramwin, pair, format!("{:02x}", arr[a])),
+                                None => { wprintw(self.ramwin, format!("{:02x}", arr[a])); }
+                            }
+                        }
+                        wprintw(self.ramwin, " ");
+                        continue 'cols;
+                    }
                     for regn in range(0, 16) {
                         let regf = (regn % 6) as i16 + 1;
                         let regval = if regn == 0 { pc } else {regs.arr[regn] & 0xfffe};
                         if celln == regval as uint {
                         // print in colour
                             wattron(self.ramwin, COLOR_PAIR(regf));
-                            wprintw(self.ramwin, format!("{:02x}{:02x} ", r.arr[celln], r.arr[celln + 1]));
+                            wprintw(self.ramwin, format!("{:02x}{:02x} ", arr[celln], arr[celln + 1]));
                             self.reglit[regn] = true;
                             wattroff(self.ramwin, COLOR_PAIR(regf));
                             continue 'cols;
                         }
+                    }
+                    let tainted = match *taint {
+                        Some(ref t) => t.is_tainted(celln as u16) || t.is_tainted((celln + 1) as u16),
+                        None => false
+                    };
+                    if tainted {
+                        colprint(self.ramwin, TAINTCOL, format!("{:02x}{:02x}", arr[celln], arr[celln + 1]));
+                        wprintw(self.ramwin, " ");
+                        continue 'cols;
                     }
                         // normal print
-                    wprintw(self.ramwin, format!("{:02x}{:02x} ", r.arr[celln], r.arr[celln + 1]));
+                    wprintw(self.ramwin, format!("{:02x}{:02x} ", arr[celln], arr[celln + 1]));
                 }
             }
 
             wprintw(self.ramwin, "   ");
             for col in range(0u,16u) {
-                let cellv = asciicast(r.arr[row * 16 + col]);
+                let cellv = asciicast(arr[row * 16 + col]);
                 wprintw(self.ramwin, format!("{:c}", cellv as char));
             }
         }
         wrefresh(self.ramwin);
     }
 
-    fn draw_regs(&self, r: mem::Regs, inst: cpu::Instruction) {
+    // switch the left pane to p, or back to RAM if it already shows p
+    pub fn toggle_pane(&mut self, p: Pane) {
+        self.pane = if self.pane == p { RamPane } else { p };
+        werase(self.ramwin);
+        box_(self.ramwin, 0, 0);
+    }
+
+    // W/X state of each 256-byte page, shown over the RAM pane
+    fn draw_pagemap(&mut self, r: &mem::Ram, pc: u16) {
+        mvwprintw(self.ramwin,0, 10, "   Page map   ");
+        self.reglit = [false,..16];
+        let status = if r.depstatus { "DEP on " } else { "DEP off" };
+        mvwprintw(self.ramwin, 1, 2, format!("{}   W: writable  X: execute-only  *: pc", status));
+        mvwprintw(self.ramwin, 3, 2, "       0  1  2  3  4  5  6  7  8  9  a  b  c  d  e  f");
+        for row in range(0u, 16) {
+            wmove(self.ramwin, 4 + row as i32, 2);
+            wprintw(self.ramwin, format!("{:x}000  ", row));
+            for col in range(0u, 16) {
+                let page = row * 16 + col;
+                let mark = if page == (pc >> 8) as uint { "*" } else { " " };
+                if r.deparr[page] {
+                    colprint(self.ramwin, 2, format!(" W{}", mark))
+                } else {
+                    colprint(self.ramwin, 1, format!(" X{}", mark))
+                }
+            }
+        }
+        wrefresh(self.ramwin);
+    }
+
+    // backtrace frames, then the words above sp with the saved return addresses marked
+    fn draw_stack(&mut self, cpu: &cpu::Cpu) {
+        mvwprintw(self.ramwin,0, 10, "   Backtrace   ");
+        self.reglit = [false,..16];
+        let mut row = 1;
+        for line in stack::describe(cpu, &self.symbols).iter() {
+            if row > RAMHEIGHT / 2 { break }
+            mvwprintw(self.ramwin, row, 2, format!("{:66s}", *line));
+            row += 1;
+        }
+        mvwprintw(self.ramwin, row + 1, 2, "Stack");
+        let slots : ~[u16] = cpu.frames.iter().map(|f| f.slot).collect();
+        let sp = cpu.regs.arr[1] & 0xfffe;
+        for ix in range(0, RAMHEIGHT - row - 4) {
+            let addr = sp as uint + 2 * ix as uint;
+            let y = row + 2 + ix;
+            if addr > 0xfffe {
+                mvwprintw(self.ramwin, y, 2, format!("{:66s}", ""));
+                continue
+            }
+            let w = cpu.ram.peek(addr as u32) as u16 | cpu.ram.peek(addr as u32 + 1) as u16 << 8;
+            let line = format!("{:04x}: {:04x} {:56s}", addr, w, self.symbols.label(w));
+            if slots.contains(&(addr as u16)) {
+                colmvprintw(self.ramwin, y, 2, 3, line);
+            } else {
+                mvwprintw(self.ramwin, y, 2, line);
+            }
+        }
+        wrefresh(self.ramwin);
+    }
+
+    // heap chunks with broken links in red and the ones the last step changed highlighted
+    fn draw_heap(&mut self, cpu: &cpu::Cpu) {
+        mvwprintw(self.ramwin,0, 10, "   Heap   ");
+        self.reglit = [false,..16];
+        let view = match self.heap {
+            Some(ref mut v) => { v.update(cpu); v }
+            None => return
+        };
+        mvwprintw(self.ramwin, 1, 2, format!("{:66s}", format!("base {:04x}   red: bad links  blue: changed by the last instruction", view.base)));
+        let mut row = 3;
+        for e in view.entries(cpu).iter() {
+            if row > RAMHEIGHT - 4 { break }
+            let data : ~[~str] = range(0u16, 8).map(|k| format!("{:02x}", cpu.ram.peek((e.chunk.addr + 6 + k) as u32))).collect();
+            let line = format!("{:66s}", format!("{}  {}", e.describe(), data.concat()));
+            if e.problems.len() > 0 {
+                colmvprintw(self.ramwin, row, 2, 1, line);
+            } else if e.changes.len() > 0 {
+                colmvprintw(self.ramwin, row, 2, 4, line);
+            } else {
+                mvwprintw(self.ramwin, row, 2, line);
+            }
+            row += 1;
+            let mut notes = e.problems.clone();
+            notes.push_all(e.changes);
+            if notes.len() > 0 {
+                mvwprintw(self.ramwin, row, 2, format!("{:66s}", format!("      {}", notes.connect(", "))));
+                row += 1;
+            }
+        }
+        let gone = view.gone();
+        if gone.len() > 0 && row < RAMHEIGHT - 2 {
+            let addrs : ~[~str] = gone.iter().map(|a| format!("{:04x}", *a)).collect();
+            mvwprintw(self.ramwin, row, 2, format!("{:66s}", format!("gone: {}", addrs.connect(" "))));
+            row += 1;
+        }
+        while row < RAMHEIGHT - 1 {
+            mvwprintw(self.ramwin, row, 2, format!("{:66s}", ""));
+            row += 1;
+        }
+        wrefresh(self.ramwin);
+    }
+
+    fn draw_regs(&self, r: mem::Regs, inst: cpu::Instruction, taint: &Option<~Taint>) {
         mvwprintw(self.regwin,0, 10, "   Registers   ");
         let mut linect = 0;
         for regn in range(0, 16) {
@@ -156,17 +342,39 @@ impl Gui {
             if regn % 4 == 3 {
                 s = format!("R{:02i} {:04x}", regn, r.arr[regn]);
             } else if regn == 0 { 
-                s = format!("PC  {:04x} ", inst.memloc)
+                s = format!("PC  {:04x} ", match self.regpc { Some(pc) => pc, None => inst.memloc })
             } else {
                 s = format!("R{:02i} {:04x} ", regn, r.arr[regn])
             }
             if regn % 4 == 0 { linect += 1; wmove(self.regwin, linect, 1);}
-            if self.reglit[regn] {
+            let tainted = match *taint {
+                Some(ref t) => t.reg_tainted(regn as uint),
+                None => false
+            };
+            if self.regcursor == Some(regn as uint) {
+                colprint(self.regwin, CURSORCOL, s)
+            } else if self.edits.regs.contains(&(regn as u8)) {
+                colprint(self.regwin, EDITCOL, s)
+            } else if self.reglit[regn] {
                 colprint(self.regwin, (regn % 6 +1) as i16, s)
+            } else if tainted {
+                colprint(self.regwin, TAINTCOL, s)
             } else {
                 wprintw(self.regwin, s);
             }
         }
+        wmove(self.regwin, 5, 1);
+        for (ix, &(name, mask)) in command::FLAGS.iter().enumerate() {
+            let flag = if r.arr[2] & mask != 0 { name.to_ascii_upper() } else { name.to_owned() };
+            if self.regcursor == Some(16 + ix) {
+                colprint(self.regwin, CURSORCOL, flag)
+            } else if self.edits.regs.contains(&2) {
+                colprint(self.regwin, EDITCOL, flag)
+            } else {
+                wprintw(self.regwin, flag);
+            }
+            wprintw(self.regwin, " ");
+        }
         mvwprintw(self.regwin, 5,10,format!("{:20s}", inst.to_string()));
         wrefresh(self.regwin);
     }
@@ -205,15 +413,52 @@ impl Gui {
         box_(self.asmwin, 0, 0);
         box_(self.dbgwin, 0, 0);
         */
-        self.draw_ram(cpu.ram, cpu.regs, cpu.inst.memloc);
-        self.draw_regs(cpu.regs, cpu.inst);
+        match self.pane {
+            RamPane => self.draw_ram(&cpu.ram, cpu.regs, cpu.inst.memloc, &cpu.taint),
+            PagePane => self.draw_pagemap(&cpu.ram, cpu.inst.memloc),
+            StackPane => self.draw_stack(cpu),
+            HeapPane => self.draw_heap(cpu)
+        }
+        self.draw_regs(cpu.regs, cpu.inst, &cpu.taint);
         //self.draw_inst(cpu.inst);
         self.draw_debug(cpu.buf);
         self.draw_disasm(cpu.inst.memloc);
-        mvprintw(LINES - 2, 0, "s: step, c: continue, f: fast-forward, b: add breakpoint, d: redraw, q: quit");
+        mvprintw(LINES - 2, 0, ": commands, s: step, c: continue, f: fast-forward, b: add breakpoint, a: assemble, x: xrefs, e: edit, E: edit registers, t: backtrace, p: page map, h: heap, d: redraw, q: quit");
         refresh();
     }
 
+    // a console command line with history (up/down), completion of commands, registers and
+    // `names` (tab) and editing (backspace); None if escape abandons it
+    pub fn readline(&self, start: &str, history: &mut History, names: &[~str]) -> Option<~str> {
+        let mut line = start.to_owned();
+        let width = (DBGWIDTH - 5) as uint;
+        loop {
+            let shown = if line.len() > width { line.slice_from(line.len() - width) } else { line.as_slice() };
+            mvwprintw(self.dbgwin, DBGHEIGHT - 2, 1, format!(": {:36s}", shown));
+            wmove(self.dbgwin, DBGHEIGHT - 2, 3 + shown.len() as i32);
+            wrefresh(self.dbgwin);
+            match wgetch(self.dbgwin) {
+                10 | 13 | KEY_ENTER => { history.push(line); return Some(line) },
+                27 => return None,
+                8 | 127 | KEY_BACKSPACE => { line.pop_char(); },
+                KEY_UP => match history.prev() { Some(l) => line = l, None => () },
+                KEY_DOWN => match history.next() { Some(l) => line = l, None => () },
+                9 => {
+                    let found = command::complete(line, names);
+                    if found.len() == 1 {
+                        line = format!("{} ", found[0]);
+                    } else if found.len() > 1 {
+                        line = command::common_prefix(found);
+                        let words : ~[&str] = found.iter().map(|w| w.words().last().unwrap_or("")).collect();
+                        mvwprintw(self.dbgwin, DBGHEIGHT - 3, 1, format!("{:38s}", words.connect(" ")));
+                    }
+                },
+                c if c >= 32 && c < 127 => line.push_char(c as u8 as char),
+                _ => ()
+            }
+        }
+    }
+
     pub fn getstring(&self) -> ~str {
         let mut s = ~"";
         echo();
@@ -0,0 +1,131 @@
+// Heap inspector for the microcorruption malloc/free levels
+//
+// Chunks are a doubly linked list, each with a six byte header ahead of its data:
+// prev, next, then (size << 1) | in-use; the ends of the list point at themselves or at
+// the base. The walk starts at the heap base and follows next until it comes back to
+// the base, revisits a chunk or leaves memory. Each chunk is checked against its
+// neighbours (next->prev and prev->next should point back at it, and next should
+// follow the data), and compared with the chunks before the last step so a malloc,
+// free or overflow shows up as it happens: the session walks the heap before every
+// instruction while a view is open.
+
+use cpu::Cpu;
+use mem::{Mem, MemUtil};
+
+static HEADER : u16 = 6;
+static MAXCHUNKS : uint = 64;
+
+#[deriving(Clone, Eq)]
+pub struct Chunk {
+    addr: u16,
+    prev: u16,
+    next: u16,
+    size: u16,
+    inuse: bool
+}
+
+pub struct Entry {
+    chunk: Chunk,
+    problems: ~[~str],          //inconsistent links
+    changes: ~[~str]            //fields that changed in the last step, or "new"
+}
+
+#[deriving(Clone)]
+pub struct HeapView {
+    base: u16,
+    steps: uint,                //cpu.steps when before was chosen
+    current: ~[Chunk],
+    before: ~[Chunk],           //the walk from before the last step
+    last: Option<(uint, ~[Chunk])>  //the walk before the step taken at cpu.steps = n
+}
+
+pub fn walk(cpu: &Cpu, base: u16) -> ~[Chunk] {
+    let mut out : ~[Chunk] = ~[];
+    let mut addr = base;
+    while out.len() < MAXCHUNKS && addr as uint + HEADER as uint <= 0x10000 {
+        if out.iter().any(|c| c.addr == addr) { break }
+        let word = cpu.ram.loadw(addr + 4);
+        let c = Chunk { addr: addr, prev: cpu.ram.loadw(addr), next: cpu.ram.loadw(addr + 2),
+                        size: word >> 1, inuse: word & 1 != 0 };
+        addr = c.next;
+        out.push(c);
+        if addr == base { break }
+    }
+    out
+}
+
+fn check(cpu: &Cpu, chunks: &[Chunk], ix: uint) -> ~[~str] {
+    let c = &chunks[ix];
+    let mut out = ~[];
+    let back = cpu.ram.loadw(c.next);
+    if c.next != c.addr && back != c.addr { out.push(format!("next->prev is {:04x}", back)) }
+    let fwd = cpu.ram.loadw(c.prev + 2);
+    if c.prev != c.addr && fwd != c.addr { out.push(format!("prev->next is {:04x}", fwd)) }
+    let end = c.addr as uint + HEADER as uint + c.size as uint;
+    if c.next > c.addr && c.next as uint != end {
+        out.push(format!("data ends at {:04x}", end))
+    }
+    if end > 0x10000 { out.push(~"size runs past memory") }
+    if ix + 1 == chunks.len() && c.next != c.addr && c.next != chunks[0].addr {
+        out.push(format!("next {:04x} does not end the list", c.next))
+    }
+    out
+}
+
+fn changes(c: &Chunk, before: &[Chunk]) -> ~[~str] {
+    let old = match before.iter().find(|b| b.addr == c.addr) {
+        Some(b) => b,
+        None => return if before.len() > 0 { ~[~"new"] } else { ~[] }
+    };
+    let mut out = ~[];
+    if old.prev != c.prev { out.push(format!("prev was {:04x}", old.prev)) }
+    if old.next != c.next { out.push(format!("next was {:04x}", old.next)) }
+    if old.size != c.size { out.push(format!("size was {:x}", old.size)) }
+    if old.inuse != c.inuse { out.push((if c.inuse { "allocated" } else { "freed" }).to_owned()) }
+    out
+}
+
+impl HeapView {
+    pub fn new(base: u16) -> HeapView {
+        HeapView { base: base, steps: 0, current: ~[], before: ~[], last: None }
+    }
+
+    // remember the chunks as they are before the CPU steps
+    pub fn before_step(&mut self, cpu: &Cpu) {
+        self.last = Some((cpu.steps, walk(cpu, self.base)));
+    }
+
+    // walk the heap again; if the CPU has stepped, compare with the walk from before that
+    // step, or show no changes when none was taken
+    pub fn update(&mut self, cpu: &Cpu) {
+        let chunks = walk(cpu, self.base);
+        if self.current.len() == 0 || cpu.steps != self.steps {
+            self.before = match self.last {
+                Some((n, ref w)) if n + 1 == cpu.steps => w.clone(),
+                _ => chunks.clone()
+            };
+            self.steps = cpu.steps;
+        }
+        self.current = chunks;
+    }
+
+    pub fn entries(&self, cpu: &Cpu) -> ~[Entry] {
+        range(0, self.current.len()).map(|ix| {
+            let c = self.current[ix];
+            Entry { chunk: c, problems: check(cpu, self.current, ix), changes: changes(&c, self.before) }
+        }).collect()
+    }
+
+    // chunks that were in the list before the last step and are not now, e.g. merged by free
+    pub fn gone(&self) -> ~[u16] {
+        self.before.iter().filter(|b| !self.current.iter().any(|c| c.addr == b.addr)).map(|b| b.addr).collect()
+    }
+}
+
+impl Entry {
+    pub fn describe(&self) -> ~str {
+        let c = &self.chunk;
+        format!("{:04x} {} size {:4x} prev {:04x} next {:04x} data {:04x}", c.addr,
+                if c.inuse { "used" } else { "free" }, c.size, c.prev, c.next, c.addr + HEADER)
+    }
+}
@@ -0,0 +1,141 @@
+// Level pack manifests: which images make up a pack, how to load them and,
+// optionally, an input sequence known to unlock the door.
+
+use conf;
+use conf::Section;
+use cpu::Cpu;
+use device::Device;
+use runner;
+use runner::Unlocked;
+use std::io::File;
+use std::os;
+use std::str;
+use super::{str2bytes, password_bytes};
+
+pub static DEFAULT_MANIFEST : &'static str = "images/levels.cfg";
+
+pub struct Level {
+    name: ~str,
+    image: Path,
+    device: ~str,
+    load: Option<u32>,
+    callgates: Option<bool>,
+    dep: bool,
+    hsm: Option<~str>,
+    inputs: ~[~str]
+}
+
+impl Level {
+    fn from_section(sec: &Section, dir: &Path) -> Result<Level, ~str> {
+        let image = match sec.get("image") {
+            Some(i) => dir.join(i),
+            None => return Err(format!("[{}] no image given", sec.name))
+        };
+        let callgates = match sec.get("callgates") {
+            Some(_) => Some(try!(sec.get_bool("callgates", true))),
+            None => None
+        };
+        Ok(Level {
+            name: sec.name.clone(),
+            image: image,
+            device: sec.get("device").unwrap_or(::device::DEFAULT_DEVICE.to_owned()),
+            load: try!(sec.get_num("load")),
+            callgates: callgates,
+            dep: try!(sec.get_bool("dep", true)),
+            hsm: sec.get("hsm"),
+            inputs: sec.get_all("input")
+        })
+    }
+
+    // a freshly reset cpu with the level loaded
+    pub fn cpu(&self) -> Result<Cpu, ~str> {
+        let image = try!(read_image(&self.image));
+        let mut dev = try!(Device::load(self.device.as_slice(), None));
+        match self.load { Some(l) => dev.load = l, None => () }
+        match self.callgates { Some(g) => dev.callgates = g, None => () }
+        let mut cpu = Cpu::init(image, &dev);
+        cpu.depgates = self.dep;
+        cpu.hsm_password = self.hsm.as_ref().map(|p| password_bytes(p.as_slice()));
+        Ok(cpu)
+    }
+}
+
+pub fn load_manifest(path: &str) -> Result<~[Level], ~str> {
+    let p = Path::new(path);
+    let text = match File::open(&p).read_to_str() {
+        Ok(t) => t,
+        Err(e) => return Err(format!("Could not read {}: {}", path, e))
+    };
+    let dir = p.dir_path();
+    let mut levels = ~[];
+    for sec in try!(conf::parse(text)).iter() {
+        levels.push(try!(Level::from_section(sec, &dir)));
+    }
+    Ok(levels)
+}
+
+// .hex images are the microcorruption dumps (whitespace separated big-endian words),
+// anything else is read as raw bytes
+pub fn read_image(path: &Path) -> Result<~[u8], ~str> {
+    let bytes = match File::open(path).read_to_end() {
+        Ok(b) => b,
+        Err(e) => return Err(format!("Could not read {}: {}", path.display(), e))
+    };
+    if path.extension_str() != Some("hex") { return Ok(bytes) }
+    let text = match str::from_utf8(bytes) {
+        Some(t) => t,
+        None => return Err(format!("{}: not a text file", path.display()))
+    };
+    let mut out = ~[];
+    for word in text.words() {
+        match ::std::num::from_str_radix::<u16>(word, 16) {
+            Some(w) => { out.push((w >> 8) as u8); out.push((w & 0xff) as u8) },
+            None => return Err(format!("{}: bad word '{}'", path.display(), word))
+        }
+    }
+    Ok(out)
+}
+
+// uctf levels
+pub fn list(manifest: &str) {
+    let levels = match load_manifest(manifest) {
+        Ok(l) => l,
+        Err(e) => { println!("{}", e); os::set_exit_status(1); return }
+    };
+    println!("{:20s} {:20s} {:6s} {:16s} {:5s} {:3s} {:3s} {}",
+             "Level", "Image", "Load", "Device", "Gates", "DEP", "HSM", "Solution");
+    for l in levels.iter() {
+        let load = match l.load { Some(a) => format!("{:04x}", a), None => ~"-" };
+        let gates = match l.callgates { Some(true) => "on", Some(false) => "off", None => "-" };
+        println!("{:20s} {:20s} {:6s} {:16s} {:5s} {:3s} {:3s} {}",
+                 l.name, l.image.filename_display().to_str(), load, l.device, gates,
+                 if l.dep { "on" } else { "off" },
+                 if l.hsm.is_some() { "yes" } else { "-" },
+                 if l.inputs.len() > 0 { l.inputs.connect(" | ") } else { ~"-" });
+    }
+}
+
+// uctf verify: run every stored solution headlessly
+pub fn verify(manifest: &str) {
+    let levels = match load_manifest(manifest) {
+        Ok(l) => l,
+        Err(e) => { println!("{}", e); os::set_exit_status(1); return }
+    };
+    let mut failed = 0u;
+    for l in levels.iter() {
+        if l.inputs.len() == 0 {
+            println!("{:20s} skipped (no solution)", l.name);
+            continue
+        }
+        let inputs : ~[~[u8]] = l.inputs.iter().map(|i| str2bytes(i.as_slice())).collect();
+        let result = match l.cpu() {
+            Ok(mut cpu) => runner::run(&mut cpu, inputs, runner::STEPLIMIT),
+            Err(e) => runner::Fault(e)
+        };
+        match result {
+            Unlocked => println!("{:20s} unlocked", l.name),
+            r => { println!("{:20s} FAILED: {}", l.name, r.describe()); failed += 1 }
+        }
+    }
+    if failed > 0 { os::set_exit_status(1) }
+}
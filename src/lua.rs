@@ -0,0 +1,64 @@
+// Bindings to the parts of the Lua 5.1 C API used by the debugger scripts
+
+use std::libc::{c_int, c_char, c_void, size_t, ptrdiff_t};
+
+pub enum LuaState {}
+
+pub type CFunction = extern "C" fn(l: *mut LuaState) -> c_int;
+
+pub static OK : c_int = 0;
+pub static GLOBALSINDEX : c_int = -10002;
+pub static TNIL : c_int = 0;
+pub static TSTRING : c_int = 4;
+
+#[link(name = "lua5.1")]
+extern "C" {
+    pub fn luaL_newstate() -> *mut LuaState;
+    pub fn luaL_openlibs(l: *mut LuaState);
+    pub fn lua_close(l: *mut LuaState);
+    pub fn luaL_loadfile(l: *mut LuaState, filename: *c_char) -> c_int;
+    pub fn luaL_loadstring(l: *mut LuaState, s: *c_char) -> c_int;
+    pub fn lua_pcall(l: *mut LuaState, nargs: c_int, nresults: c_int, errfunc: c_int) -> c_int;
+    pub fn lua_error(l: *mut LuaState) -> c_int;
+    pub fn luaL_where(l: *mut LuaState, level: c_int);
+    pub fn lua_concat(l: *mut LuaState, n: c_int);
+    pub fn lua_type(l: *mut LuaState, idx: c_int) -> c_int;
+    pub fn lua_isnumber(l: *mut LuaState, idx: c_int) -> c_int;
+    pub fn lua_isstring(l: *mut LuaState, idx: c_int) -> c_int;
+    pub fn lua_tointeger(l: *mut LuaState, idx: c_int) -> ptrdiff_t;
+    pub fn lua_tolstring(l: *mut LuaState, idx: c_int, len: *mut size_t) -> *c_char;
+    pub fn lua_touserdata(l: *mut LuaState, idx: c_int) -> *mut c_void;
+    pub fn lua_pushnil(l: *mut LuaState);
+    pub fn lua_pushinteger(l: *mut LuaState, n: ptrdiff_t);
+    pub fn lua_pushlstring(l: *mut LuaState, s: *c_char, len: size_t);
+    pub fn lua_pushlightuserdata(l: *mut LuaState, p: *mut c_void);
+    pub fn lua_pushcclosure(l: *mut LuaState, f: CFunction, n: c_int);
+    pub fn lua_createtable(l: *mut LuaState, narr: c_int, nrec: c_int);
+    pub fn lua_setfield(l: *mut LuaState, idx: c_int, k: *c_char);
+}
+
+// the stack index of a C closure's upvalue i (from 1)
+pub fn upvalueindex(i: c_int) -> c_int {
+    GLOBALSINDEX - i
+}
+
+// no argument, or nil
+pub unsafe fn is_none(l: *mut LuaState, idx: c_int) -> bool {
+    lua_type(l, idx) <= TNIL
+}
+
+pub unsafe fn push_bytes(l: *mut LuaState, bytes: &[u8]) {
+    lua_pushlstring(l, bytes.as_ptr() as *c_char, bytes.len() as size_t)
+}
+
+// the string (or number, converted) at idx
+pub unsafe fn to_bytes(l: *mut LuaState, idx: c_int) -> Option<~[u8]> {
+    if lua_isstring(l, idx) == 0 { return None }
+    let mut len = 0 as size_t;
+    let p = lua_tolstring(l, idx, &mut len);
+    Some(::std::vec::raw::from_buf_raw(p as *u8, len as uint))
+}
+
+pub unsafe fn to_str(l: *mut LuaState, idx: c_int) -> ~str {
+    to_bytes(l, idx).map_or(~"(not a string)", |b| b.iter().map(|&c| c as char).collect())
+}
@@ -4,100 +4,286 @@ extern crate ncurses;
 extern crate collections;
 extern crate getopts;
 extern crate rand;
+extern crate sync;
+extern crate time;
 
-use cpu::{Cpu, GetInput, Normal, Success, Off};
-use std::io::{File, stdin};
+use command::Command;
+use device::Device;
+use std::io::stdin;
 use std::os;
-use getopts::{optflag, getopts};
+use getopts::{optflag, optopt, optmulti, getopts};
 use nc = ncurses;
+use session::{Session, Frontend};
 
 mod cpu;
 mod gui;
 mod mem;
+mod conf;
+mod device;
+mod flash;
+mod runner;
+mod level;
+mod asm;
+mod analysis;
+mod cfg;
+mod dump;
+mod stack;
+mod taint;
+mod symex;
+mod fuzz;
+mod pool;
+mod gadgets;
+mod payload;
+mod heap;
+mod command;
+mod session;
+mod script;
+mod lua;
 
 fn print_usage(s: &str) {
     println!("Usage: {} [options] INPUT", s);
+    println!("       {} levels [MANIFEST]    list the levels in a pack (default {})", s, level::DEFAULT_MANIFEST);
+    println!("       {} verify [MANIFEST]    check the stored solutions unlock each level", s);
+    println!("       {} asm SOURCE           assemble a file or a string like 'mov #0xff00, sr; call #0x10'", s);
+    println!("       {} payload STATEMENTS   build input like 'pad 16; word 0x4528; asm \"mov #0x7f, r15\"; call 0x10'", s);
+    println!("       {} cfg --function F INPUT  write the control-flow graph of function F (address or name) as DOT", s);
+    println!("       {} solve [--target ADDR] INPUT  find input that unlocks the door, or reaches ADDR", s);
+    println!("       {} fuzz [--execs N] [-o DIR] INPUT  fuzz the input, saving unlocks and crashes to DIR", s);
+    println!("       {} brute [--charset C] [--length N] INPUT  try every input up to N characters", s);
+    println!("       {} gadgets [--pops REG] [--writes-mem] INPUT  list the ROP gadgets in the image", s);
     println!("Options: -d --disasm      print disassembled input");
+    println!("         --format F       -d output: {} (default text)", dump::FORMATS);
+    println!("         -x --cpux        enable the MSP430X extended instruction set");
+    println!("         --device NAME    device profile (default {})", device::DEFAULT_DEVICE);
+    println!("         --device-file F  read device profiles from F instead of devices.cfg");
+    println!("         --hsm-password P password stored in the HSM (x-prefix for hex)");
+    println!("         --smash-check    stop when a RET does not match the CALL that pushed it");
+    println!("         --taint          track input bytes through memory and registers");
+    println!("         --org ADDR       address to assemble at (default 4400), or of the payload's first byte (default 0)");
+    println!("         -o --output FILE output file for cfg (default FUNCTION.dot), directory for fuzz (default fuzz-out)");
+    println!("         --target ADDR    address for solve to reach instead of the unlock gate");
+    println!("         --execs N        inputs for fuzz to try (default {})", fuzz::DEFAULT_EXECS);
+    println!("         -j --jobs N      worker threads for fuzz and brute (default one per core)");
+    println!("         --charset C      characters for brute (default {})", fuzz::DEFAULT_CHARSET);
+    println!("         --length N       longest input for brute (default {})", fuzz::DEFAULT_LENGTH);
+    println!("         --ex CMD         run a debugger command instead of starting the TUI; repeatable");
+    println!("         --script FILE    run a Lua debugger script instead of starting the TUI, after any --ex");
+    println!("         --pops REG       gadgets that pop REG (e.g. r15)");
+    println!("         --writes-mem     gadgets that write memory");
 }
 
-fn print_disasm(v: &[u8]) {
-    let listing = cpu::disassemble(v);
-    for (lineno, line) in listing.move_iter() {
-        println!("{:04x}: {}", lineno, line)
-    }
+// the TUI: the windows and the console's history
+struct Tui {
+    windows: gui::Gui,
+    history: command::History
 }
 
-fn event_loop(mut cpu: Cpu, mut windows: gui::Gui, mut breakpoints: ~[u16]) -> (uint,~[u16]) {
-    loop {
-        match nc::wgetch(nc::stdscr) {
-            115 => {                //s
-                cpu.step();
-                match &cpu.status {
-                    &Off => {
-                        cpu.buf.push_str("CPU OFF\n");
-                        windows.render(&cpu);
-                    },
-                    &Success => {
-                        cpu.buf.push_str("Success! Door unlocked\n"); 
-                        windows.render(&cpu);
-                    },
-                    &GetInput(_) => { cpu.status = GetInput(str2bytes(getstring(cpu.buf))) },
-                    &Normal => {windows.render(&cpu)},
-                }
-                windows.render(&cpu);
-            }
-            c @ 99 | c @ 102 => {
-                'outer : loop {            //c or f
-                    cpu.step();
-                    match &cpu.status {
-                        &Off => {
-                            cpu.buf.push_str("CPU OFF\n");
-                            windows.render(&cpu);
-                            break 'outer
-                        },
-                        &Success => {
-                            cpu.buf.push_str("Success! Door unlocked"); 
-                            windows.render(&cpu);
-                            break 'outer
-                        },
-                        &GetInput(_) => { 
-                            windows.render(&cpu);
-                            cpu.status = GetInput(str2bytes(getstring(cpu.buf)));
-                            break 'outer 
-                        },
-                        &Normal => if c == 99 {windows.render(&cpu)},
-                    }
-                    for &num in breakpoints.iter() { 
-                        if cpu.inst.memloc == num {
-                            cpu.buf.push_str(format!("Break {:04x}\n", num)); windows.render(&cpu); break 'outer 
-                        } 
+impl Frontend for Tui {
+    fn show(&mut self, s: &Session) {
+        self.windows.edits = s.edits.clone();
+        self.windows.heap = s.heap.clone();
+        self.windows.render(&s.cpu)
+    }
+
+    fn input(&mut self, s: &Session) -> Option<~str> {
+        Some(getstring(s.cpu.buf))
+    }
+
+    fn reload(&mut self, s: &Session) {
+        self.windows.listing = s.listing.clone();
+        self.windows.symbols = analysis::Analysis::run(s.cpu.ram.bytes(), s.cpu.cpux);
+    }
+
+    fn pane(&mut self, s: &Session, p: gui::Pane) {
+        if p == gui::HeapPane {
+            let same = match (&self.windows.heap, &s.heap) {
+                (&Some(ref a), &Some(ref b)) => a.base == b.base,
+                _ => false
+            };
+            self.windows.heap = s.heap.clone();
+            if !same && self.windows.pane == gui::HeapPane { return }      //stay on the pane with the new base
+        }
+        self.windows.toggle_pane(p)
+    }
+
+    // hex editor on the RAM pane: arrows move the cursor, hex digits overwrite a nibble at a time
+    fn edit(&mut self, s: &mut Session, addr: u16) {
+        let other = self.windows.pane;
+        if other != gui::RamPane { self.windows.toggle_pane(other) }
+        s.say("Editing memory: arrows move, hex digits write, enter or q ends");
+        let mut cur = addr;
+        let mut low = false;            //next digit is the low nibble
+        loop {
+            self.windows.cursor = Some(cur);
+            self.show(s);
+            let key = nc::wgetch(nc::stdscr);
+            let digit = match key {
+                48..57 => Some(key - 48),
+                97..102 => Some(key - 87),
+                65..70 => Some(key - 55),
+                _ => None
+            };
+            match digit {
+                Some(d) => {
+                    let old = s.cpu.ram.peek(cur as u32);
+                    let new = if low { (old & 0xf0) | d as u8 } else { (old & 0x0f) | (d as u8 << 4) };
+                    match s.write(cur, [new]) {
+                        Ok(true) => self.reload(s),
+                        Ok(false) => (),
+                        Err(e) => s.say(e.as_slice())
                     }
+                    if low { cur += 1 }
+                    low = !low;
+                    continue
                 }
-            },
-            113 => return (1, ~[]),
-            98 => {                 //b  -> breakpoint
-                let s = getstring("Enter breakpoint location:\n");
-                let noption = std::u16::parse_bytes(s.trim().to_owned().into_bytes(),16);
-                match noption {
-                    Some(n) => {
-                        breakpoints.push(n & 0xfffe);
-                        cpu.buf.push_str(format!("Breakpoint added: {:04x}\n", n & 0xfffe));
-                        windows.render(&cpu);
-                    },
-                    None => cpu.buf.push_str(format!("Failed to add breakpoint {}\n", s.clone()))
+                None => ()
+            }
+            low = false;
+            match key {
+                nc::KEY_LEFT => cur -= 1,
+                nc::KEY_RIGHT => cur += 1,
+                nc::KEY_UP => cur -= 16,
+                nc::KEY_DOWN => cur += 16,
+                10 | 13 | 27 | 113 => break,
+                _ => ()
+            }
+        }
+        self.windows.cursor = None;
+        self.show(s);
+    }
+
+    // register editor: arrows move over r0-r15 and the flags below them, hex digits shift
+    // into the register and backspace drops its last digit, space toggles a flag. The pc
+    // is only set on enter, as the digits on the way there can make it odd
+    fn edit_regs(&mut self, s: &mut Session) {
+        s.say("Editing registers: arrows move, hex digits and backspace change, space toggles a flag, enter (sets pc) or q ends");
+        let mut cur = 0u;
+        let mut pc = None;
+        loop {
+            self.windows.regpc = pc;
+            self.windows.regcursor = Some(cur);
+            self.show(s);
+            let key = nc::wgetch(nc::stdscr);
+            let digit = match key {
+                48..57 => Some(key - 48),
+                97..102 => Some(key - 87),
+                65..70 => Some(key - 55),
+                _ => None
+            };
+            if cur < 16 {
+                let old = match pc {
+                    Some(v) if cur == 0 => v,
+                    _ if cur == 0 => s.cpu.inst.memloc,
+                    _ => s.cpu.regs.arr[cur]
+                };
+                let new = match digit {
+                    Some(d) => Some((old << 4) | d as u16),
+                    None if key == nc::KEY_BACKSPACE || key == 127 || key == 8 => Some(old >> 4),
+                    None => None
+                };
+                match new {
+                    Some(v) if cur == 0 => { pc = Some(v); continue },
+                    Some(v) => { s.set_reg(cur as u8, v).unwrap(); continue },
+                    None => ()
                 }
-            },
-            114 => return (0, breakpoints),               //r 
-            100 => { nc::endwin(); windows.render(&cpu); nc::refresh(); },        //d
-            _ => ()
+            } else if key == 32 {
+                let (_, mask) = command::FLAGS[cur - 16];
+                let sr = s.cpu.regs.arr[2];
+                s.set_reg(2, sr ^ mask).unwrap();
+                continue
+            }
+            match key {
+                nc::KEY_LEFT if cur > 0 => cur -= 1,
+                nc::KEY_RIGHT if cur < 19 => cur += 1,
+                nc::KEY_UP if cur >= 4 => cur -= 4,
+                nc::KEY_DOWN if cur < 16 => cur += 4,
+                10 | 13 => {
+                    match pc.map_or(Ok(()), |v| s.set_reg(0, v)) {
+                        Ok(()) => break,
+                        Err(e) => s.say(e.as_slice())
+                    }
+                },
+                27 | 113 => break,
+                _ => ()
+            }
         }
+        self.windows.regcursor = None;
+        self.windows.regpc = None;
+        self.show(s);
     }
 }
 
+// read a command at the console, starting with `start`; an empty line repeats the last one
+fn console(session: &mut Session, tui: &mut Tui, start: &str) -> Option<Command> {
+    let names = session.names();
+    let line = match tui.windows.readline(start, &mut tui.history, names) {
+        Some(l) if l.trim().len() > 0 => l,
+        Some(_) => match tui.history.last() {
+            Some(l) => l,
+            None => return None
+        },
+        None => return None
+    };
+    match command::parse(line.as_slice()) {
+        Ok(cmd) => Some(cmd),
+        Err(e) => { session.say(e.as_slice()); tui.windows.render(&session.cpu); None }
+    }
+}
+
+fn event_loop(session: &mut Session, tui: &mut Tui) {
+    loop {
+        let cmd = match nc::wgetch(nc::stdscr) {
+            115 => Some(command::Step(1)),          //s
+            99 => Some(command::Animate),           //c
+            102 => Some(command::Continue),         //f
+            116 => Some(command::Backtrace),        //t
+            112 => Some(command::Pages),            //p  -> DEP page map
+            114 => Some(command::Reset),            //r
+            113 => Some(command::Quit),             //q
+            100 => Some(command::Redraw),           //d
+            104 if tui.windows.pane == gui::HeapPane => Some(command::Heap(None)),
+            58 => console(session, tui, ""),                //:  -> command line
+            98 => console(session, tui, "break "),          //b  -> breakpoint
+            120 => console(session, tui, "xrefs "),         //x  -> cross references
+            97 => console(session, tui, "asm "),            //a  -> assemble into memory
+            104 => console(session, tui, "heap "),          //h  -> heap chunks
+            101 => console(session, tui, "edit "),          //e  -> hex editor
+            69 => Some(command::EditRegs),                  //E  -> register editor
+            _ => None
+        };
+        match cmd {
+            Some(command::Redraw) => { nc::endwin(); nc::refresh(); },
+            _ => ()
+        }
+        match cmd {
+            Some(c) => if !session.exec(c, tui as &mut Frontend) { return },
+            None => tui.windows.render(&session.cpu)
+        }
+    }
+}
 
 fn main() {
     let args = os::args();
-    let opts = ~[optflag("d", "disasm", "Print disassembled file")];
+    let opts = ~[optflag("d", "disasm", "Print disassembled file"),
+                 optflag("x", "cpux", "Enable MSP430X extended instructions"),
+                 optopt("", "device", "Device profile", "NAME"),
+                 optopt("", "device-file", "Device profile file", "FILE"),
+                 optopt("", "hsm-password", "Password stored in the HSM", "PASS"),
+                 optflag("", "smash-check", "Stop when a return address is overwritten"),
+                 optflag("", "taint", "Track where input bytes end up"),
+                 optopt("", "org", "Assembly origin", "ADDR"),
+                 optopt("", "function", "Function to graph", "ADDR"),
+                 optopt("", "target", "Address for solve to reach", "ADDR"),
+                 optopt("", "execs", "Fuzzer runs", "N"),
+                 optopt("j", "jobs", "Worker threads", "N"),
+                 optopt("", "charset", "Characters to brute force with", "CHARS"),
+                 optopt("", "length", "Longest input to brute force", "N"),
+                 optopt("", "pops", "Only gadgets popping this register", "REG"),
+                 optflag("", "writes-mem", "Only gadgets writing memory"),
+                 optmulti("", "ex", "Debugger command to run without the TUI", "CMD"),
+                 optopt("", "script", "Debugger script to run without the TUI", "FILE"),
+                 optopt("o", "output", "Output file", "FILE"),
+                 optopt("", "format", "Disassembly format", "FORMAT")];
     let matches = match getopts(args.tail(), opts) {
         Ok(m) => m,
         Err(_) => { println!("Argument parse failed"); print_usage(args[0]); return }
@@ -108,28 +294,110 @@ fn main() {
         print_usage(args[0]);
         return;
     };
-    let memval = File::open(&Path::new(fpath)).read_to_end();
-    let v = match memval {
+    let manifest = if matches.free.len() > 1 {
+        matches.free[1].clone()
+    } else {
+        level::DEFAULT_MANIFEST.to_owned()
+    };
+    match fpath.as_slice() {
+        "levels" => return level::list(manifest),
+        "verify" => return level::verify(manifest),
+        "asm" => {
+            match addr_opt(&matches, "org") {
+                Some(org) => return asm::run(matches.free.slice_from(1).connect(" "), org.unwrap_or(0x4400)),
+                None => return
+            }
+        },
+        "payload" => {
+            match addr_opt(&matches, "org") {
+                Some(org) => return payload::run(matches.free.slice_from(1).connect(" "), org.unwrap_or(0)),
+                None => return
+            }
+        },
+        _ => ()
+    }
+    let imagepath = match fpath.as_slice() {
+        "cfg" | "solve" | "fuzz" | "brute" | "gadgets" if matches.free.len() > 1 => matches.free[1].clone(),
+        "cfg" | "solve" | "fuzz" | "brute" | "gadgets" => { print_usage(args[0]); return },
+        _ => fpath.clone()
+    };
+    let v = match level::read_image(&Path::new(imagepath)) {
         Ok(v) => v,
         Err(e) => fail!(e)
     };
+    let devname = matches.opt_str("device").unwrap_or(device::DEFAULT_DEVICE.to_owned());
+    let mut dev = match Device::load(devname, matches.opt_str("device-file")) {
+        Ok(d) => d,
+        Err(e) => { println!("{}", e); return }
+    };
+    if matches.opt_present("x") { dev.cpux = true }
+    let hsm_password = matches.opt_str("hsm-password").map(|p| password_bytes(p));
     if matches.opt_present("d") {
-        print_disasm(v);
+        let format = matches.opt_str("format").unwrap_or(~"text");
+        match dump::print(v, &dev, format) {
+            Ok(()) => (),
+            Err(e) => { println!("{}", e); os::set_exit_status(1) }
+        }
         return
     }
+    if fpath.as_slice() == "cfg" {
+        return cfg::run(v, &dev, matches.opt_str("function"), matches.opt_str("o"))
+    }
+    if fpath.as_slice() == "gadgets" {
+        let pops = match matches.opt_str("pops") {
+            Some(r) => match asm::parse_reg(r.as_slice()) {
+                Some(n) => Some(n),
+                None => { println!("Unknown register {}", r); os::set_exit_status(1); return }
+            },
+            None => None
+        };
+        let filter = gadgets::Filter { pops: pops, writes_mem: matches.opt_present("writes-mem") };
+        return gadgets::run(v, &dev, filter)
+    }
+    if fpath.as_slice() == "solve" {
+        match addr_opt(&matches, "target") {
+            Some(target) => return symex::run(v, &dev, target, hsm_password),
+            None => return
+        }
+    }
+    let workers = matches.opt_str("j").and_then(|n| from_str::<uint>(n)).unwrap_or(pool::default_workers());
+    if workers < 1 { println!("-j needs at least one worker"); os::set_exit_status(1); return }
+    if fpath.as_slice() == "fuzz" {
+        let execs = matches.opt_str("execs").and_then(|n| from_str::<uint>(n)).unwrap_or(fuzz::DEFAULT_EXECS);
+        return fuzz::run(v, &dev, hsm_password, matches.opt_str("o"), execs, workers)
+    }
+    if fpath.as_slice() == "brute" {
+        let charset = matches.opt_str("charset").unwrap_or(fuzz::DEFAULT_CHARSET.to_owned());
+        let length = matches.opt_str("length").and_then(|n| from_str::<uint>(n)).unwrap_or(fuzz::DEFAULT_LENGTH);
+        return fuzz::brute(v, &dev, hsm_password, charset, length, workers)
+    }
+
 
+    let mut session = Session::new(v, &dev, hsm_password, matches.opt_present("smash-check"),
+                                   matches.opt_present("taint"));
+    let mut ex = matches.opt_strs("ex");
+    match matches.opt_str("script") {
+        Some(path) => ex.push(format!("source {}", path)),
+        None => ()
+    }
+    if ex.len() > 0 { return session::headless(&mut session, ex) }
+    let mut tui = Tui { windows: gui::Gui::init(), history: command::History::new() };
+    tui.reload(&session);
+    tui.windows.render(&session.cpu);
+    event_loop(&mut session, &mut tui);
+    nc::endwin();
+}
 
-    let mut breakpoints : ~[u16] = ~[];
-    let mut status = 0;
-    while status == 0 {
-        let cpu = Cpu::init(v);
-        let mut windows = gui::Gui::init();
-        windows.listing = cpu::disassemble(cpu.ram.arr);
-        windows.render(&cpu);
-        let (s, b) = event_loop(cpu, windows, breakpoints.clone());
-        breakpoints = b;
-        status = s;
-        nc::endwin();
+// a hex address option, Some(None) when it is absent; None once a bad one is reported
+fn addr_opt(matches: &getopts::Matches, name: &str) -> Option<Option<u16>> {
+    let s = match matches.opt_str(name) {
+        Some(s) => s,
+        None => return Some(None)
+    };
+    let digits = if s.starts_with("0x") { s.slice_from(2) } else { s.as_slice() };
+    match std::u16::parse_bytes(digits.as_bytes(), 16) {
+        Some(n) => Some(Some(n)),
+        None => { println!("Bad --{} address {}", name, s); os::set_exit_status(1); None }
     }
 }
 
@@ -152,6 +420,13 @@ fn str2bytes(s : &str) -> ~[u8] {
     out
 }
 
+// like str2bytes, without the NUL terminators
+fn password_bytes(s: &str) -> ~[u8] {
+    let mut out = str2bytes(s);
+    while out.last() == Some(&0u8) { out.pop(); }
+    out
+}
+
 fn getstring(buf: &str) -> ~str {
     nc::endwin();
     print!("{}", buf);
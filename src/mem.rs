@@ -2,10 +2,19 @@
 #[allow(dead_code)];
 
 use std::fmt;
+use flash;
+use flash::FlashCtl;
+use sync::Arc;
+
+static PAGE : uint = 256;
 
 pub trait Mem {
     fn loadb(&self, addr: u16) -> u8;
     fn storeb(&mut self, addr: u16, val: u8) -> bool ;
+
+    // 20-bit (MSP430X) access - by default wraps into the 16-bit space
+    fn loadbx(&self, addr: u32) -> u8 { self.loadb(addr as u16) }
+    fn storebx(&mut self, addr: u32, val: u8) -> bool { self.storeb(addr as u16, val) }
 }
 
 pub trait MemUtil {
@@ -13,6 +22,10 @@ pub trait MemUtil {
     fn storew(&mut self, addr: u16, val: u16) -> bool;
     fn load(&self, addr: u16, byteflag: bool) -> u16;
     fn store(&mut self, addr: u16, val: u16, byteflag: bool) -> bool ;
+    fn loadwx(&self, addr: u32) -> u16;
+    fn storewx(&mut self, addr: u32, val: u16) -> bool;
+    fn loada(&self, addr: u32) -> u32;
+    fn storea(&mut self, addr: u32, val: u32) -> bool;
 }
 
 impl<M: Mem> MemUtil for M {
@@ -39,53 +52,328 @@ impl<M: Mem> MemUtil for M {
             self.storew(addr, val)
         }
     }
+
+    fn loadwx(&self, addr: u32) -> u16 {
+        self.loadbx(addr) as u16 | (self.loadbx(addr + 1) as u16 << 8)
+    }
+
+    fn storewx(&mut self, addr: u32, val: u16) -> bool {
+        self.storebx(addr, (val & 0xff) as u8) && self.storebx(addr + 1, (val >> 8) as u8)
+    }
+
+    // address-words are stored as two words, upper 12 bits of the second are ignored
+    fn loada(&self, addr: u32) -> u32 {
+        (self.loadwx(addr) as u32 | (self.loadwx(addr + 2) as u32 << 16)) & 0xfffff
+    }
+
+    fn storea(&mut self, addr: u32, val: u32) -> bool {
+        self.storewx(addr, val as u16) && self.storewx(addr + 2, ((val >> 16) & 0xf) as u16)
+    }
+}
+
+#[deriving(Clone, Eq)]
+pub enum RegionKind {
+    RamKind,
+    FlashKind,
+    InfoKind,
+    SfrKind,
+    PeriphKind
+}
+
+// inclusive address range of a device memory map
+#[deriving(Clone)]
+pub struct Region {
+    kind: RegionKind,
+    start: u32,
+    end: u32
+}
+
+impl Region {
+    pub fn contains(&self, addr: u32) -> bool {
+        addr >= self.start && addr <= self.end
+    }
+}
+
+// why the last store was refused
+#[deriving(Clone)]
+pub enum Fault {
+    DepWrite(u32),          //write to an execute-only page
+    DepExec(u32),           //execution from a writable page
+    Unmapped(u32),
+    FlashLocked(u32),       //flash written while locked or not in write/erase mode
+    FlashKey(u32, u8),      //controller register written without the 0xa5 key
+    FlashSegA(u32),         //information segment A written while LOCKA is set
+    StackSmash(u16, u16, u16),  //return address at slot changed from expected to actual
+}
+
+impl fmt::Show for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match *self {
+            DepWrite(addr) => format!("DEP violation: write to 0x{:04x}, page 0x{:02x} is execute-only",
+                                      addr, addr >> 8),
+            DepExec(addr) => format!("DEP violation: jump to 0x{:04x}, page 0x{:02x} is writable",
+                                     addr, addr >> 8),
+            Unmapped(addr) => format!("write to unmapped address 0x{:04x}", addr),
+            FlashLocked(addr) => format!("write to flash at 0x{:04x} without the controller in write/erase mode", addr),
+            FlashKey(addr, key) => format!("flash controller register 0x{:04x} written with key 0x{:02x}", addr, key),
+            FlashSegA(addr) => format!("write to information segment A at 0x{:04x} while LOCKA is set", addr),
+            StackSmash(slot, expected, actual) => format!("stack smashed: return address at 0x{:04x} was 0x{:04x}, returned to 0x{:04x}",
+                                                          slot, expected, actual)
+        };
+        write!(f.buf, "{}", s)
+    }
+}
+
+// A copy-on-write array in 256-byte pages: clones share the base and only copy the
+// pages they write, so a snapshot can be cloned cheaply (and across tasks)
+pub struct Paged<T> {
+    base: Arc<~[T]>,                //the contents as of the last share(), shared between clones
+    pages: ~[Option<~[T]>]          //private copies of the pages written since
+}
+
+impl<T: Clone + Send + Share> Clone for Paged<T> {
+    fn clone(&self) -> Paged<T> {
+        Paged { base: self.base.clone(), pages: self.pages.clone() }
+    }
+}
+
+impl<T: Clone + Send + Share> Paged<T> {
+    pub fn new(len: uint, fill: T) -> Paged<T> {
+        Paged { base: Arc::new(::std::vec::from_elem(len, fill)),
+                pages: range(0u, (len + PAGE - 1) / PAGE).map(|_| None).collect() }
+    }
+
+    // nothing allocated, for what is only tracked on request
+    pub fn empty() -> Paged<T> {
+        Paged { base: Arc::new(~[]), pages: ~[] }
+    }
+
+    pub fn len(&self) -> uint {
+        self.base.get().len()
+    }
+
+    pub fn get(&self, ix: uint) -> T {
+        match self.pages[ix / PAGE] {
+            Some(ref p) => p[ix % PAGE].clone(),
+            None => self.base.get()[ix].clone()
+        }
+    }
+
+    pub fn set(&mut self, ix: uint, val: T) {
+        let n = ix / PAGE;
+        if self.pages[n].is_none() {
+            let end = ::std::cmp::min((n + 1) * PAGE, self.len());
+            self.pages[n] = Some(self.base.get().slice(n * PAGE, end).to_owned());
+        }
+        match self.pages[n] {
+            Some(ref mut p) => p[ix % PAGE] = val,
+            None => ()
+        }
+    }
+
+    pub fn to_vec(&self) -> ~[T] {
+        range(0, self.len()).map(|ix| self.get(ix)).collect()
+    }
+
+    // the entries of the pages written since the last share(), with their indices
+    pub fn written(&self) -> ~[(uint, T)] {
+        let mut out = ~[];
+        for (n, p) in self.pages.iter().enumerate() {
+            match *p {
+                Some(ref p) => for (ix, v) in p.iter().enumerate() { out.push((n * PAGE + ix, v.clone())) },
+                None => ()
+            }
+        }
+        out
+    }
+
+    // fold the written pages into a new shared base, so clones made afterwards start
+    // with nothing to copy
+    pub fn share(&mut self) {
+        if self.pages.iter().all(|p| p.is_none()) { return }
+        self.base = Arc::new(self.to_vec());
+        for p in self.pages.mut_iter() { *p = None }
+    }
 }
 
 pub struct Ram {
-    arr: [u8, ..0x10000],
+    lower: Paged<u8>,        //the lower 64KiB
+    ext: Paged<u8>,          //memory above 0xffff, only allocated in CPUX mode
+    map: ~[Region],          //empty = flat, everything writable
+    flash: Option<FlashCtl>,
+    fault: Option<Fault>,
     depstatus: bool,
     deparr: [bool, ..0x100], //true = writeable, false = executable
+    pc: u16,                 //instruction being executed, for writers
+    writers: Paged<u16>,     //last instruction to write each byte, empty unless tracked
+}
+
+// fixed arrays have no Clone, so copy them by hand
+impl Clone for Ram {
+    fn clone(&self) -> Ram {
+        Ram { lower: self.lower.clone(), ext: self.ext.clone(), map: self.map.clone(), flash: self.flash,
+              fault: self.fault, depstatus: self.depstatus, deparr: self.deparr, pc: self.pc,
+              writers: self.writers.clone() }
+    }
 }
 
 impl Ram {
     pub fn new() -> Ram {
-        Ram { arr: [0, ..0x10000], depstatus : false, deparr : [false,..0x100] }
+        Ram { lower: Paged::new(0x10000, 0u8), ext: Paged::empty(), map: ~[], flash: None, fault: None,
+              depstatus : false, deparr : [false,..0x100], pc: 0, writers: Paged::empty() }
+    }
+
+    // a copy of the lower 64KiB, for the disassembler and analyses
+    pub fn bytes(&self) -> ~[u8] {
+        self.lower.to_vec()
+    }
+
+    // fold everything written so far into the shared bases
+    pub fn share(&mut self) {
+        self.lower.share();
+        self.ext.share();
+        self.writers.share();
+    }
+
+    // raw access, bypassing DEP, the memory map and the flash controller
+    pub fn peek(&self, addr: u32) -> u8 {
+        let addr = addr & 0xfffff;
+        if addr < 0x10000 {
+            self.lower.get(addr as uint)
+        } else if ((addr - 0x10000) as uint) < self.ext.len() {
+            self.ext.get((addr - 0x10000) as uint)
+        } else {
+            0
+        }
+    }
+
+    pub fn poke(&mut self, addr: u32, val: u8) {
+        let addr = addr & 0xfffff;
+        if addr < 0x10000 {
+            self.lower.set(addr as uint, val)
+        } else if ((addr - 0x10000) as uint) < self.ext.len() {
+            self.ext.set((addr - 0x10000) as uint, val)
+        }
+    }
+
+    pub fn enable_flash(&mut self, ctl: FlashCtl) {
+        self.flash = Some(ctl);
+        flash::mirror(self);
+    }
+
+    fn write(&mut self, addr: u32, val: u8) -> bool {
+        if self.depstatus && addr < 0x10000 && !self.deparr[addr >> 8] {
+            self.fault = Some(DepWrite(addr));
+            return false
+        }
+        let kind = match self.region(addr) {
+            Some(r) => r.kind,
+            None if self.map.len() == 0 => RamKind,
+            None => { self.fault = Some(Unmapped(addr)); return false }
+        };
+        match self.flash {
+            Some(ctl) if ctl.is_register(addr) => return flash::write_reg(self, addr, val),
+            Some(_) if kind == FlashKind || kind == InfoKind => return flash::write_flash(self, addr, kind, val),
+            _ => ()
+        }
+        self.poke(addr, val);
+        self.record_write(addr);
+        true
     }
 
-    pub fn loadimage(&mut self, image: &[u8], startfrom: u16) {
+    pub fn track_writes(&mut self) {
+        if self.writers.len() == 0 {
+            self.writers = Paged::new(0x10000, 0u16);
+        }
+    }
+
+    // note that the current instruction wrote addr
+    pub fn record_write(&mut self, addr: u32) {
+        if addr < 0x10000 && self.writers.len() > 0 { self.writers.set(addr as uint, self.pc) }
+    }
+
+    pub fn last_writer(&self, addr: u16) -> Option<u16> {
+        if self.writers.len() > 0 { Some(self.writers.get(addr as uint)) } else { None }
+    }
+
+    pub fn region(&self, addr: u32) -> Option<Region> {
+        for r in self.map.iter() {
+            if r.contains(addr) { return Some(r.clone()) }
+        }
+        None
+    }
+
+    // debugger patches: written directly like loadimage, but refused on execute-only
+    // pages unless force is set
+    pub fn patch(&mut self, addr: u32, bytes: &[u8], force: bool) -> Result<(), Fault> {
+        for ix in range(0, bytes.len() as u32) {
+            let a = (addr + ix) & 0xfffff;
+            if !force && self.depstatus && a < 0x10000 && !self.deparr[a >> 8] {
+                return Err(DepWrite(a))
+            }
+        }
+        self.loadimage(bytes, addr);
+        Ok(())
+    }
+
+    // allocate the upper 960KiB of the 20-bit address space
+    pub fn enable_ext(&mut self) {
+        if self.ext.len() == 0 {
+            self.ext = Paged::new(0xf0000, 0u8);
+        }
+    }
+
+    // programs memory directly, bypassing DEP and the memory map
+    pub fn loadimage(&mut self, image: &[u8], startfrom: u32) {
         for (ix, &byte) in image.iter().enumerate() {
-            self.storeb(startfrom + ix as u16, byte);
+            self.poke(startfrom + ix as u32, byte);
         }
     }
 }
 
 impl Mem for Ram {
     fn loadb(&self, addr: u16) -> u8 {
-        self.arr[addr]
+        self.lower.get(addr as uint)
     }
     fn storeb(&mut self, addr: u16, val: u8) -> bool {
-        if self.depstatus && !self.deparr[addr >> 8] {
-            false
-        } else {
-            self.arr[addr] = val;
-            true
+        self.write(addr as u32, val)
+    }
+    fn loadbx(&self, addr: u32) -> u8 {
+        self.peek(addr)
+    }
+    fn storebx(&mut self, addr: u32, val: u8) -> bool {
+        let addr = addr & 0xfffff;
+        if addr >= 0x10000 && ((addr - 0x10000) as uint) >= self.ext.len() {
+            self.fault = Some(Unmapped(addr));
+            return false
         }
+        self.write(addr, val)
     }
 }
 
 pub struct Regs {
-    arr: [u16, ..16]
+    arr: [u16, ..16],
+    hi: [u8, ..16]          //bits 19:16 of each register in CPUX mode
 }
 
 impl Regs {
     pub fn load(&self, addr: u8) -> u16 {
         self.arr[addr]
     }
+    //word writes to a register clear the upper bits
     pub fn store(&mut self, addr: u8, val: u16) {
-        self.arr[addr] = val
+        self.arr[addr] = val;
+        self.hi[addr] = 0
+    }
+    pub fn loada(&self, addr: u8) -> u32 {
+        (self.hi[addr] as u32 << 16) | self.arr[addr] as u32
+    }
+    pub fn storea(&mut self, addr: u8, val: u32) {
+        self.arr[addr] = val as u16;
+        self.hi[addr] = ((val >> 16) & 0xf) as u8
     }
     pub fn new() -> Regs {
-        Regs { arr: [0, ..16] }
+        Regs { arr: [0, ..16], hi: [0, ..16] }
     }
 }
 
@@ -108,8 +396,9 @@ impl fmt::Show for Ram {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f.buf, "|---------------------RAM----------------------|\n");
         let mut wasvalid = false;
-        for i in range(0, self.arr.len()/16) {
-            let v = self.arr.slice(16*i, 16*i + 16);
+        let arr = self.bytes();
+        for i in range(0, arr.len()/16) {
+            let v = arr.slice(16*i, 16*i + 16);
             match v {
                 [0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0] => {
                     if wasvalid {
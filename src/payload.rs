@@ -0,0 +1,164 @@
+// Exploit payload builder
+//
+// A payload is a list of statements separated by newlines or ';':
+//   pad N [BYTE]       N copies of BYTE (default 'A')
+//   word W, ...        16-bit values, little-endian
+//   byte B, ...
+//   ascii "text"       the text, with the assembler's escapes
+//   hex 414243         raw bytes
+//   asm "source"       assembled at the address the bytes will land at
+//   call ADDR          the same as asm "call #ADDR"
+//   org ADDR           the address of the payload's first byte (default the getsn buffer, or 0)
+// Values take the assembler's forms: 0x4528, 16, 'A' and sums of them.
+
+use asm;
+use std::ascii::StrAsciiExt;
+use std::os;
+use symex::show_input;
+
+pub struct Piece {
+    offset: uint,
+    bytes: ~[u8],
+    text: ~str
+}
+
+pub struct Payload {
+    org: u16,
+    pieces: ~[Piece]
+}
+
+fn number(s: &str, max: int) -> Result<int, ~str> {
+    let n = try!(asm::parse_number(s));
+    if n < -(max + 1) / 2 || n > max { return Err(format!("{} does not fit", s.trim())) }
+    Ok(n)
+}
+
+fn values(args: &str, max: int) -> Result<~[int], ~str> {
+    let mut out = ~[];
+    for a in asm::split_top(args, ',').iter() { out.push(try!(number(a.as_slice(), max))) }
+    Ok(out)
+}
+
+fn hex(args: &str) -> Result<~[u8], ~str> {
+    let digits : ~str = args.chars().filter(|c| !c.is_whitespace()).collect();
+    if digits.len() % 2 != 0 { return Err(~"odd number of hex digits") }
+    let mut out = ~[];
+    for chunk in digits.as_bytes().chunks(2) {
+        match ::std::u8::parse_bytes(chunk, 16) {
+            Some(b) => out.push(b),
+            None => return Err(format!("bad hex {}", args.trim()))
+        }
+    }
+    Ok(out)
+}
+
+impl Payload {
+    pub fn build(src: &str, org: u16) -> Result<Payload, ~str> {
+        let mut p = Payload { org: org, pieces: ~[] };
+        for text in asm::split_top(src.replace("\n", ";"), ';').move_iter() {
+            let text = text.trim().to_owned();
+            if text.len() == 0 { continue }
+            let bytes = try!(p.statement(text.as_slice()).map_err(|e| format!("{}: {}", text, e)));
+            let offset = p.len();
+            if bytes.len() > 0 { p.pieces.push(Piece { offset: offset, bytes: bytes, text: text }) }
+        }
+        Ok(p)
+    }
+
+    fn statement(&mut self, text: &str) -> Result<~[u8], ~str> {
+        let (cmd, args) = match text.find(|c: char| c.is_whitespace()) {
+            Some(ix) => (text.slice_to(ix), text.slice_from(ix).trim()),
+            None => (text, "")
+        };
+        let here = (self.org as uint + self.len()) as u16;
+        match cmd.to_ascii_lower().as_slice() {
+            "pad" => {
+                let parts : ~[~str] = asm::split_top(args, ' ').move_iter().filter(|p| p.len() > 0).collect();
+                let b = match parts.len() {
+                    1 => 0x41,
+                    2 => try!(number(parts[1], 0xff)),
+                    _ => return Err(~"expected pad N [BYTE]")
+                };
+                let n = try!(number(parts[0], 0xffff));
+                if n < 0 { return Err(~"negative length") }
+                Ok(::std::vec::from_elem(n as uint, b as u8))
+            },
+            "word" => {
+                let mut out = ~[];
+                for w in try!(values(args, 0xffff)).iter() {
+                    out.push((*w & 0xff) as u8);
+                    out.push((*w >> 8 & 0xff) as u8);
+                }
+                Ok(out)
+            },
+            "byte" => Ok(try!(values(args, 0xff)).iter().map(|b| *b as u8).collect()),
+            "ascii" => asm::parse_string(args),
+            "hex" => hex(args),
+            "asm" if args.len() >= 2 && args.starts_with("\"") && args.ends_with("\"") => {
+                asm::assemble(args.slice(1, args.len() - 1), here)
+            },
+            "asm" => Err(~"expected asm \"source\""),
+            "call" => asm::assemble(format!("call #{}", args), here),
+            "org" => {
+                if self.pieces.len() > 0 { return Err(~"org must come first") }
+                self.org = try!(number(args, 0xffff)) as u16;
+                Ok(~[])
+            },
+            _ => Err(format!("unknown statement {}", cmd))
+        }
+    }
+
+    pub fn len(&self) -> uint {
+        self.pieces.iter().fold(0, |a, p| a + p.bytes.len())
+    }
+
+    pub fn bytes(&self) -> ~[u8] {
+        let mut out = ~[];
+        for p in self.pieces.iter() { out.push_all(p.bytes) }
+        out
+    }
+
+    // offset, address, bytes (16 to a line) and the statement that made them
+    pub fn listing(&self) -> ~[~str] {
+        let mut out = ~[];
+        for p in self.pieces.iter() {
+            for (k, chunk) in p.bytes.chunks(16).enumerate() {
+                let hex : ~[~str] = chunk.iter().map(|b| format!("{:02x}", *b)).collect();
+                let off = p.offset + k * 16;
+                let text = if k == 0 { p.text.as_slice() } else { "" };
+                out.push(format!("+{:04x} {:04x}: {:48s} {}", off, (self.org as uint + off) as u16,
+                                 hex.connect(" "), text));
+            }
+        }
+        out
+    }
+
+    // NULs, which end strcpy-style copies, and bytes past what getsn will take
+    pub fn warnings(&self, limit: Option<u16>) -> ~[~str] {
+        let mut out = ~[];
+        for p in self.pieces.iter() {
+            for (k, b) in p.bytes.iter().enumerate() {
+                if *b == 0 { out.push(format!("warning: NUL at +{:04x} ({}), string copies stop there", p.offset + k, p.text)) }
+            }
+        }
+        match limit {
+            Some(n) if self.len() > n as uint => {
+                out.push(format!("warning: {} bytes, getsn only reads {}", self.len(), n))
+            },
+            _ => ()
+        }
+        out
+    }
+}
+
+// uctf payload [--org ADDR] STATEMENTS
+pub fn run(src: &str, org: u16) {
+    match Payload::build(src, org) {
+        Ok(p) => {
+            for line in p.listing().iter() { println!("{}", *line) }
+            for w in p.warnings(None).iter() { println!("{}", *w) }
+            println!("{}", show_input(p.bytes()));
+        },
+        Err(e) => { println!("{}", e); os::set_exit_status(1) }
+    }
+}
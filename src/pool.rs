@@ -0,0 +1,132 @@
+// Worker pool: one emulator task per core, each restarting from a shared snapshot
+//
+// Jobs are sets of inputs for successive getsn calls. Every worker holds a clone of
+// the snapshot, whose memory, write tracking, edge counts and taint are shared
+// copy-on-write, and runs each job on a fresh clone of that. Results come back on
+// one channel with the job and the edges it hit, and the pool keeps running totals
+// of the outcomes.
+
+use cpu::Cpu;
+use runner;
+use runner::{Outcome, Unlocked, Halted, Fault, OutOfInput, StepLimit};
+use std::rt;
+use time;
+
+static QUEUED : uint = 4;           //jobs handed to each worker ahead of its results
+
+pub struct Job {
+    id: uint,
+    inputs: ~[~[u8]]
+}
+
+pub struct Done {
+    job: Job,
+    outcome: Outcome,
+    edges: ~[(u16, u8)],            //edges hit and their counts, if the snapshot tracks them
+    worker: uint
+}
+
+pub struct Stats {
+    execs: uint,
+    unlocks: uint,
+    faults: uint,
+    halts: uint,
+    hangs: uint,
+    starved: uint,                  //ran out of input
+    started: f64                    //precise_time_s at creation
+}
+
+impl Stats {
+    pub fn describe(&self) -> ~str {
+        let secs = time::precise_time_s() - self.started;
+        let rate = if secs > 0.0 { self.execs as f64 / secs } else { 0.0 };
+        format!("{} execs ({:.0f}/s), {} unlocks, {} faults, {} halts, {} hangs, {} out of input",
+                self.execs, rate, self.unlocks, self.faults, self.halts, self.hangs, self.starved)
+    }
+
+    fn count(&mut self, o: &Outcome) {
+        self.execs += 1;
+        match *o {
+            Unlocked => self.unlocks += 1,
+            Fault(_) => self.faults += 1,
+            Halted => self.halts += 1,
+            StepLimit(_) => self.hangs += 1,
+            OutOfInput => self.starved += 1
+        }
+    }
+}
+
+pub struct Pool {
+    workers: ~[Sender<Option<Job>>],
+    results: Receiver<Done>,
+    pending: ~[uint],               //jobs sent to each worker and not yet returned
+    stats: Stats
+}
+
+// the number of workers to use when not given
+pub fn default_workers() -> uint {
+    rt::default_sched_threads()
+}
+
+impl Pool {
+    pub fn new(snapshot: &Cpu, workers: uint, maxsteps: uint) -> Pool {
+        let (donetx, results) = channel();
+        let mut senders = ~[];
+        for w in range(0, workers) {
+            let (tx, jobs) = channel::<Option<Job>>();
+            let done = donetx.clone();
+            let base = snapshot.clone();
+            spawn(proc() {
+                loop {
+                    let job = match jobs.recv() {
+                        Some(j) => j,
+                        None => break
+                    };
+                    let mut cpu = base.clone();
+                    let outcome = runner::run(&mut cpu, job.inputs.as_slice(), maxsteps);
+                    let edges = cpu.edge_counts();
+                    done.send(Done { job: job, outcome: outcome, edges: edges, worker: w });
+                }
+            });
+            senders.push(tx);
+        }
+        Pool { workers: senders, results: results, pending: range(0, workers).map(|_| 0u).collect(),
+               stats: Stats { execs: 0, unlocks: 0, faults: 0, halts: 0, hangs: 0, starved: 0,
+                              started: time::precise_time_s() } }
+    }
+
+    // whether another job can be queued without waiting
+    pub fn has_room(&self) -> bool {
+        self.pending.iter().any(|&n| n < QUEUED)
+    }
+
+    pub fn in_flight(&self) -> uint {
+        self.pending.iter().fold(0, |a, &n| a + n)
+    }
+
+    // hand the job to the least busy worker
+    pub fn submit(&mut self, job: Job) {
+        let mut best = 0;
+        for (w, &n) in self.pending.iter().enumerate() {
+            if n < self.pending[best] { best = w }
+        }
+        self.pending[best] += 1;
+        self.workers[best].send(Some(job));
+    }
+
+    // the next result, blocking until one is ready
+    pub fn recv(&mut self) -> Done {
+        let d = self.results.recv();
+        self.pending[d.worker] -= 1;
+        self.stats.count(&d.outcome);
+        d
+    }
+
+    // wait for the jobs in flight, then stop the workers
+    pub fn finish(&mut self) -> ~[Done] {
+        let mut out = ~[];
+        while self.in_flight() > 0 { out.push(self.recv()) }
+        for w in self.workers.iter() { w.send(None) }
+        out
+    }
+}
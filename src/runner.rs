@@ -0,0 +1,45 @@
+// Headless execution: run a Cpu to completion, feeding canned input lines to getsn
+
+use cpu::{Cpu, GetInput, Normal, Success, Off, Faulted};
+
+pub static STEPLIMIT : uint = 1000000;
+
+pub enum Outcome {
+    Unlocked,
+    Halted,                 //CPU turned itself off
+    Fault(~str),
+    OutOfInput,             //program asked for more input than we had
+    StepLimit(uint)         //the limit that ran out
+}
+
+impl Outcome {
+    pub fn describe(&self) -> ~str {
+        match *self {
+            Unlocked => ~"door unlocked",
+            Halted => ~"CPU off",
+            Fault(ref s) => format!("fault: {}", s),
+            OutOfInput => ~"ran out of input",
+            StepLimit(n) => format!("no result after {} steps", n)
+        }
+    }
+}
+
+pub fn run(cpu: &mut Cpu, inputs: &[~[u8]], maxsteps: uint) -> Outcome {
+    let mut next = 0u;
+    for _ in range(0, maxsteps) {
+        let waiting = match cpu.status {
+            Success => return Unlocked,
+            Off => return Halted,
+            Faulted(ref f) => return Fault(format!("{}", f)),
+            GetInput(ref bytes) => bytes.len() == 0,
+            Normal => false
+        };
+        if waiting {
+            if next == inputs.len() { return OutOfInput }
+            cpu.status = GetInput(inputs[next].clone());
+            next += 1;
+        }
+        cpu.step();
+    }
+    StepLimit(maxsteps)
+}
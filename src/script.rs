@@ -0,0 +1,273 @@
+// Debugger scripts in Lua
+//
+// uctf --script FILE and the source command run a Lua 5.1 script with the standard
+// libraries and a dbg table driving the session:
+//   dbg.step([N]), dbg.continue(), dbg.run(), dbg.reset()   run, returning dbg.status()
+//   dbg.status()             "running", "input", "off", "unlocked" or "faulted"
+//   dbg.steps()              instructions executed
+//   dbg.reg(R), dbg.setreg(R, V), dbg.flags()
+//   dbg.byte(A), dbg.word(A), dbg.read(A, N), dbg.setbyte(A, V), dbg.setword(A, V), dbg.write(A, S)
+//   dbg.breakpoint(A), dbg.watch(A), dbg.delete([A]), dbg.input(TEXT)
+//   dbg.addr(A), dbg.disas(A)  the address, and the instruction there and the next address
+//   dbg.cmd(LINE)            any console command
+//   dbg.say(TEXT)            a console message; print does the same
+// Registers are numbers or names ("pc", "r15"), addresses numbers or console locations
+// ("login+10"). Commands go through the console's executor and a failing one raises a
+// Lua error, so the script stops with its file and line unless it uses pcall.
+
+use asm;
+use command;
+use command::{Command, Loc, NumTerm};
+use cpu::{GetInput, Normal, Success, Off, Faulted};
+use lua;
+use lua::LuaState;
+use mem::{Mem, MemUtil};
+use session::{Session, Frontend};
+use std::libc::{c_int, c_void, ptrdiff_t};
+
+static MAXDEPTH : uint = 8;         //scripts sourcing scripts
+
+static API : [&'static str, ..23] = [
+    "step", "continue", "run", "reset", "status", "steps", "reg", "setreg", "flags", "byte", "word",
+    "read", "setbyte", "setword", "write", "breakpoint", "watch", "delete", "input", "addr", "disas",
+    "cmd", "say"
+];
+
+static PRELUDE : &'static str = "print = function(...)
+  local t = {}
+  for i = 1, select('#', ...) do t[i] = tostring((select(i, ...))) end
+  dbg.say(table.concat(t, '\\t'))
+end";
+
+// what the dbg functions reach through their first upvalue
+struct Ctx<'a> {
+    s: &'a mut Session,
+    ui: &'a mut Frontend,
+    quit: bool                      //a command asked to leave the debugger
+}
+
+// run the script at path; false when it quits the debugger
+pub fn source(s: &mut Session, path: &str, ui: &mut Frontend) -> Result<bool, ~str> {
+    if s.scripts >= MAXDEPTH { return Err(format!("{}: scripts nested more than {} deep", path, MAXDEPTH)) }
+    s.scripts += 1;
+    let result = run(s, path, ui);
+    s.scripts -= 1;
+    result
+}
+
+fn run(s: &mut Session, path: &str, ui: &mut Frontend) -> Result<bool, ~str> {
+    let mut ctx = Ctx { s: s, ui: ui, quit: false };
+    let err = unsafe {
+        let l = lua::luaL_newstate();
+        if l.is_null() { return Err(~"Lua could not allocate a state") }
+        lua::luaL_openlibs(l);
+        register(l, &mut ctx);
+        let mut status = path.with_c_str(|p| lua::luaL_loadfile(l, p));
+        if status == lua::OK { status = lua::lua_pcall(l, 0, 0, 0) }
+        let err = if status == lua::OK { None } else { Some(lua::to_str(l, -1)) };
+        lua::lua_close(l);
+        err
+    };
+    match err {
+        _ if ctx.quit => Ok(false),
+        Some(e) => Err(e),
+        None => Ok(true)
+    }
+}
+
+unsafe fn register(l: *mut LuaState, ctx: &mut Ctx) {
+    let p = ctx as *mut Ctx as *mut c_void;
+    lua::lua_createtable(l, 0, API.len() as c_int);
+    for (ix, name) in API.iter().enumerate() {
+        lua::lua_pushlightuserdata(l, p);
+        lua::lua_pushinteger(l, ix as ptrdiff_t);
+        lua::lua_pushcclosure(l, dispatch, 2);
+        name.with_c_str(|k| lua::lua_setfield(l, -2, k));
+    }
+    "dbg".with_c_str(|k| lua::lua_setfield(l, lua::GLOBALSINDEX, k));
+    PRELUDE.with_c_str(|src| lua::luaL_loadstring(l, src));
+    lua::lua_pcall(l, 0, 0, 0);
+}
+
+// every dbg function; upvalue 2 picks which. lua_error longjmps out of here, so the
+// error is pushed once call's values are dropped. A fail! in the emulator (an illegal
+// opcode, RETI or DADD reached through dbg.step) unwinds through Lua's C frames rather
+// than raising a Lua error: the state is never closed and the debugger exits as it
+// would from the console
+extern "C" fn dispatch(l: *mut LuaState) -> c_int {
+    unsafe {
+        let ctx = &mut *(lua::lua_touserdata(l, lua::upvalueindex(1)) as *mut Ctx);
+        let name = API[lua::lua_tointeger(l, lua::upvalueindex(2)) as uint];
+        match call(name, ctx, l) {
+            Ok(n) => return n,
+            Err(e) => {
+                lua::luaL_where(l, 1);
+                lua::push_bytes(l, format!("dbg.{}: {}", name, e).as_bytes())
+            }
+        }
+        lua::lua_concat(l, 2);
+        lua::lua_error(l)
+    }
+}
+
+unsafe fn call(name: &str, c: &mut Ctx, l: *mut LuaState) -> Result<c_int, ~str> {
+    match name {
+        "step" => {
+            let n = if lua::is_none(l, 1) { 1 } else { try!(int_arg(l, 1)) };
+            if n < 1 { return Err(~"needs a positive count") }
+            try!(exec(c, command::Step(n as uint)));
+            push_status(c, l)
+        },
+        "continue" => { try!(exec(c, command::Continue)); push_status(c, l) },
+        "run" => { try!(exec(c, command::Run)); push_status(c, l) },
+        "reset" => { try!(exec(c, command::Reset)); push_status(c, l) },
+        "status" => push_status(c, l),
+        "steps" => push_int(l, c.s.cpu.steps as int),
+        "reg" => {
+            let r = try!(reg_arg(l, 1));
+            push_int(l, if r == 0 { c.s.cpu.inst.memloc } else { c.s.cpu.regs.arr[r as uint] } as int)
+        },
+        "setreg" => {
+            let r = try!(reg_arg(l, 1));
+            try!(c.s.set_reg(r, try!(int_arg(l, 2)) as u16));
+            Ok(0)
+        },
+        "flags" => {
+            lua::push_bytes(l, command::flags(c.s.cpu.regs.arr[2]).as_bytes());
+            Ok(1)
+        },
+        "byte" => { let a = try!(loc_arg(c, l, 1)); push_int(l, c.s.cpu.ram.loadb(a) as int) },
+        "word" => { let a = try!(loc_arg(c, l, 1)); push_int(l, c.s.cpu.ram.loadw(a) as int) },
+        "read" => {
+            let a = try!(loc_arg(c, l, 1));
+            let n = try!(int_arg(l, 2));
+            let bytes : ~[u8] = range(0, n).map(|i| c.s.cpu.ram.loadb(a + i as u16)).collect();
+            lua::push_bytes(l, bytes.as_slice());
+            Ok(1)
+        },
+        "setbyte" => {
+            let a = try!(loc_arg(c, l, 1));
+            let v = try!(int_arg(l, 2));
+            write(c, a, &[v as u8])
+        },
+        "setword" => {
+            let a = try!(loc_arg(c, l, 1));
+            let v = try!(int_arg(l, 2));
+            write(c, a, &[v as u8, (v >> 8) as u8])
+        },
+        "write" => {
+            let a = try!(loc_arg(c, l, 1));
+            match lua::to_bytes(l, 2) {
+                Some(bytes) => write(c, a, bytes.as_slice()),
+                None => Err(~"argument 2 should be a string")
+            }
+        },
+        "breakpoint" => { let a = try!(loc_arg(c, l, 1)); try!(exec(c, command::Break(num_loc(a)))); Ok(0) },
+        "watch" => { let a = try!(loc_arg(c, l, 1)); try!(exec(c, command::Watch(num_loc(a)))); Ok(0) },
+        "delete" => {
+            let loc = if lua::is_none(l, 1) { None } else { Some(num_loc(try!(loc_arg(c, l, 1)))) };
+            try!(exec(c, command::Delete(loc)));
+            Ok(0)
+        },
+        "input" => { let text = try!(str_arg(l, 1)); try!(exec(c, command::Input(text))); Ok(0) },
+        "addr" => { let a = try!(loc_arg(c, l, 1)); push_int(l, a as int) },
+        "disas" => {
+            let a = try!(loc_arg(c, l, 1));
+            match c.s.listing.iter().position(|&(x, _)| x == a) {
+                Some(i) => {
+                    let (_, ref text) = c.s.listing[i];
+                    lua::push_bytes(l, text.as_bytes());
+                    match c.s.listing.get(i + 1) {
+                        Some(&(next, _)) => lua::lua_pushinteger(l, next as ptrdiff_t),
+                        None => lua::lua_pushnil(l)
+                    }
+                },
+                None => { lua::lua_pushnil(l); lua::lua_pushnil(l) }
+            }
+            Ok(2)
+        },
+        "cmd" => {
+            let line = try!(str_arg(l, 1));
+            try!(exec(c, try!(command::parse(line.as_slice()))));
+            Ok(0)
+        },
+        "say" => {
+            let text = try!(str_arg(l, 1));
+            c.s.say(text.as_slice());
+            c.ui.show(&*c.s);
+            Ok(0)
+        },
+        _ => Err(~"not implemented")
+    }
+}
+
+// run a command as the console would, but as an error for the script if it fails
+fn exec(c: &mut Ctx, cmd: Command) -> Result<(), ~str> {
+    if !try!(c.s.try_exec(cmd, &mut *c.ui)) {
+        c.quit = true;
+        return Err(~"quit")
+    }
+    c.ui.show(&*c.s);
+    Ok(())
+}
+
+fn write(c: &mut Ctx, addr: u16, bytes: &[u8]) -> Result<c_int, ~str> {
+    if try!(c.s.write(addr, bytes)) { c.ui.reload(&*c.s) }
+    c.ui.show(&*c.s);
+    Ok(0)
+}
+
+fn num_loc(addr: u16) -> Loc {
+    Loc { terms: ~[(false, NumTerm(addr))] }
+}
+
+unsafe fn push_status(c: &Ctx, l: *mut LuaState) -> Result<c_int, ~str> {
+    let status = match c.s.cpu.status {
+        GetInput(ref bytes) if bytes.len() == 0 => "input",
+        GetInput(_) | Normal => "running",
+        Off => "off",
+        Success => "unlocked",
+        Faulted(_) => "faulted"
+    };
+    lua::push_bytes(l, status.as_bytes());
+    Ok(1)
+}
+
+unsafe fn push_int(l: *mut LuaState, n: int) -> Result<c_int, ~str> {
+    lua::lua_pushinteger(l, n as ptrdiff_t);
+    Ok(1)
+}
+
+unsafe fn int_arg(l: *mut LuaState, n: c_int) -> Result<int, ~str> {
+    if lua::lua_isnumber(l, n) == 0 { return Err(format!("argument {} should be a number", n)) }
+    Ok(lua::lua_tointeger(l, n) as int)
+}
+
+unsafe fn str_arg(l: *mut LuaState, n: c_int) -> Result<~str, ~str> {
+    match lua::to_bytes(l, n) {
+        Some(bytes) => Ok(bytes.iter().map(|&b| b as char).collect()),
+        None => Err(format!("argument {} should be a string", n))
+    }
+}
+
+// a number, or a location as the console takes it
+unsafe fn loc_arg(c: &Ctx, l: *mut LuaState, n: c_int) -> Result<u16, ~str> {
+    if lua::lua_type(l, n) != lua::TSTRING { return int_arg(l, n).map(|v| v as u16) }
+    let loc = try!(command::parse_loc(try!(str_arg(l, n)).as_slice()));
+    c.s.resolve(&loc)
+}
+
+// a register number or name
+unsafe fn reg_arg(l: *mut LuaState, n: c_int) -> Result<u8, ~str> {
+    if lua::lua_type(l, n) == lua::TSTRING {
+        let name = try!(str_arg(l, n));
+        return match asm::parse_reg(name.as_slice()) {
+            Some(r) => Ok(r),
+            None => Err(format!("no register {}", name))
+        }
+    }
+    match try!(int_arg(l, n)) {
+        r if r >= 0 && r < 16 => Ok(r as u8),
+        r => Err(format!("no register r{}", r))
+    }
+}
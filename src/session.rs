@@ -0,0 +1,567 @@
+// Debugger sessions: the CPU, breakpoints and watchpoints, and the commands run on them
+//
+// The TUI and headless runs share the command executor. Whatever draws the session
+// implements Frontend, which is told when to show the CPU, asked for input when a
+// getsn has none queued and told which pane a command selects. Messages go to the
+// CPU's console buffer, next to the program's own output.
+
+use analysis::Analysis;
+use asm;
+use command;
+use collections::HashSet;
+use command::{Command, Loc, Term, NumTerm, RegTerm, NameTerm, RegTarget, FlagTarget, MemTarget};
+use command::{Step, Continue, Animate, Break, Delete, Watch, Examine, Set, Write, Fill, Edit, EditRegs, Disas, Info,
+              Backtrace, Xrefs, Patch, Input, Source, Run, Reset, Pages, Heap, Redraw, Help, Quit};
+use cpu;
+use cpu::{Cpu, GetInput, Normal, Success, Off, Faulted};
+use device::Device;
+use gui;
+use heap;
+use mem::{Mem, MemUtil};
+use payload::Payload;
+use script;
+use stack;
+use super::str2bytes;
+
+pub trait Frontend {
+    // draw the CPU; called after every instruction of animate and after each command
+    fn show(&mut self, s: &Session);
+    // a line for a getsn with no queued input, None to stop and wait
+    fn input(&mut self, s: &Session) -> Option<~str>;
+    // the code changed (asm, reset) and any disassembly should be redone
+    fn reload(&mut self, s: &Session);
+    // toggle a pane
+    fn pane(&mut self, s: &Session, p: gui::Pane);
+    // let the user edit memory from addr, writing through Session::write
+    fn edit(&mut self, s: &mut Session, addr: u16);
+    // let the user edit the registers and flags, writing through Session::set_reg
+    fn edit_regs(&mut self, s: &mut Session);
+}
+
+// what was changed by hand since the CPU last ran, for highlighting
+#[deriving(Clone)]
+pub struct Edits {
+    mem: HashSet<u16>,
+    regs: ~[u8]
+}
+
+impl Edits {
+    pub fn new() -> Edits {
+        Edits { mem: HashSet::new(), regs: ~[] }
+    }
+}
+
+pub struct Session {
+    cpu: Cpu,
+    start: Cpu,                     //the CPU at reset
+    breakpoints: ~[u16],
+    watches: ~[(u16, u16)],         //address and the word last seen there
+    inputs: ~[~str],                //lines queued for getsn
+    heap: Option<heap::HeapView>,   //walked before every step once a base is given
+    edits: Edits,
+    symbols: Analysis,
+    listing: ~[(u16, ~str)],
+    scripts: uint                   //scripts being sourced, to bound their nesting
+}
+
+impl Session {
+    pub fn new(image: &[u8], dev: &Device, hsm_password: Option<~[u8]>, smash_check: bool, taint: bool) -> Session {
+        let mut cpu = Cpu::init(image, dev);
+        cpu.hsm_password = hsm_password;
+        if smash_check { cpu.enable_smash_check() }
+        if taint { cpu.enable_taint() }
+        let listing = cpu::disassemble(cpu.ram.bytes(), cpu.cpux);
+        let symbols = Analysis::run(cpu.ram.bytes(), cpu.cpux);
+        Session { start: cpu.clone(), cpu: cpu, breakpoints: ~[], watches: ~[], inputs: ~[], heap: None,
+                  edits: Edits::new(), symbols: symbols, listing: listing, scripts: 0 }
+    }
+
+    pub fn say(&mut self, s: &str) {
+        self.cpu.buf.push_str(s);
+        self.cpu.buf.push_char('\n');
+    }
+
+    pub fn resolve(&self, loc: &Loc) -> Result<u16, ~str> {
+        let mut total = 0u16;
+        for &(neg, ref t) in loc.terms.iter() {
+            let n = try!(self.term(t));
+            total = if neg { total - n } else { total + n };
+        }
+        Ok(total)
+    }
+
+    fn term(&self, t: &Term) -> Result<u16, ~str> {
+        match *t {
+            NumTerm(n) => Ok(n),
+            RegTerm(0) => Ok(self.cpu.inst.memloc),
+            RegTerm(r) => Ok(self.cpu.regs.arr[r]),
+            NameTerm(ref name) => match self.symbols.functions.iter().find(|f| f.name == *name) {
+                Some(f) => Ok(f.start),
+                None => Err(format!("unknown function {}", *name))
+            }
+        }
+    }
+
+    // the function names, for completion
+    pub fn names(&self) -> ~[~str] {
+        self.symbols.functions.iter().map(|f| f.name.clone()).collect()
+    }
+
+    pub fn reset(&mut self) {
+        self.cpu = self.start.clone();
+        self.edits = Edits::new();
+        self.heap = self.heap.as_ref().map(|h| heap::HeapView::new(h.base));
+        self.refresh_watches();
+    }
+
+    // write bytes into memory by hand, ignoring DEP; true if they changed code, which has
+    // been disassembled again
+    pub fn write(&mut self, addr: u16, bytes: &[u8]) -> Result<bool, ~str> {
+        if addr as uint + bytes.len() > 0x10000 { return Err(format!("{:04x}+{:x} runs past memory", addr, bytes.len())) }
+        match self.cpu.ram.patch(addr as u32, bytes, true) {
+            Ok(()) => (),
+            Err(f) => return Err(format!("Write refused: {}", f))
+        }
+        let end = addr as uint + bytes.len();
+        for a in range(addr as uint, end) { self.edits.mem.insert(a as u16); }
+        self.refresh_watches();
+        let pc = self.cpu.inst.memloc as uint;
+        if pc < end && addr as uint <= pc + 6 { self.cpu.refetch() }
+        if !self.listing.iter().any(|&(a, _)| a as uint + 6 > addr as uint && (a as uint) < end) { return Ok(false) }
+        self.listing = cpu::disassemble(self.cpu.ram.bytes(), self.cpu.cpux);
+        self.symbols = Analysis::run(self.cpu.ram.bytes(), self.cpu.cpux);
+        Ok(true)
+    }
+
+    // the next instruction is fetched from a new pc, which has to be even
+    pub fn set_reg(&mut self, r: u8, v: u16) -> Result<(), ~str> {
+        if r == 0 {
+            if v & 1 != 0 { return Err(format!("pc {:04x} is odd", v)) }
+            self.cpu.inst.memloc = v;
+            self.cpu.refetch();
+        } else {
+            self.cpu.regs.store(r, v);
+        }
+        if !self.edits.regs.contains(&r) { self.edits.regs.push(r) }
+        Ok(())
+    }
+
+    fn refresh_watches(&mut self) {
+        for w in self.watches.mut_iter() {
+            let (addr, _) = *w;
+            *w = (addr, self.cpu.ram.loadw(addr));
+        }
+    }
+
+    // the bytes for a line typed at the input prompt: text, x-prefixed hex, or a payload
+    // built for the getsn buffer
+    pub fn input_bytes(&mut self, line: &str) -> Result<~[u8], ~str> {
+        if !line.trim_left().starts_with("payload ") { return Ok(str2bytes(line)) }
+        let (buffer, limit) = self.cpu.pending_input().unwrap_or((0, 0xffff));
+        let p = try!(Payload::build(line.trim_left().slice_from(8), buffer));
+        let mut lines = p.listing();
+        lines.push_all(p.warnings(Some(limit)));
+        for l in lines.iter() { self.say(l.as_slice()) }
+        let mut bytes = p.bytes();
+        bytes.push(0);
+        Ok(bytes)
+    }
+
+    // answer a waiting getsn from the queue or the frontend, false if nothing came
+    fn feed(&mut self, ui: &mut Frontend) -> bool {
+        loop {
+            let line = if self.inputs.len() > 0 {
+                self.inputs.shift().unwrap()
+            } else {
+                ui.show(self);
+                match ui.input(self) {
+                    Some(l) => l,
+                    None => { self.say("Waiting for input (queue some with input TEXT)"); return false }
+                }
+            };
+            match self.input_bytes(line.as_slice()) {
+                Ok(bytes) => { self.cpu.status = GetInput(bytes); return true },
+                Err(e) => self.say(e.as_slice())
+            }
+        }
+    }
+
+    // run up to count instructions, or until something stops it
+    fn go(&mut self, count: Option<uint>, animate: bool, ui: &mut Frontend) {
+        let mut n = 0u;
+        self.edits = Edits::new();
+        loop {
+            let ended = match self.cpu.status {
+                Off | Success | Faulted(_) => true,
+                _ => false
+            };
+            if ended { return self.say("The program has ended, reset to run it again") }
+            match self.heap {
+                Some(ref mut h) => h.before_step(&self.cpu),
+                None => ()
+            }
+            self.cpu.step();
+            n += 1;
+            let (stop, waiting) = match self.cpu.status {
+                Off => (Some("CPU OFF"), false),
+                Success => (Some("Success! Door unlocked"), false),
+                Faulted(_) => (Some(""), false),            //the CPU has reported the fault
+                GetInput(ref bytes) => (None, bytes.len() == 0),
+                Normal => (None, false)
+            };
+            match stop {
+                Some("") => return,
+                Some(msg) => return self.say(msg),
+                None => ()
+            }
+            if waiting {
+                if !self.feed(ui) || count.is_none() { return }
+            }
+            if animate { ui.show(self) }
+            let pc = self.cpu.inst.memloc;
+            if count.is_none() && self.breakpoints.contains(&pc) {
+                let msg = format!("Break {:04x} {}", pc, self.symbols.label(pc));
+                return self.say(msg.as_slice())
+            }
+            let mut hit = None;
+            for w in self.watches.mut_iter() {
+                let (addr, old) = *w;
+                let now = self.cpu.ram.loadw(addr);
+                if now != old {
+                    *w = (addr, now);
+                    hit = Some(format!("Watch {:04x}: {:04x} -> {:04x}, now at {:04x} {}", addr, old, now, pc,
+                                       self.symbols.label(pc)));
+                }
+            }
+            match hit {
+                Some(msg) => return self.say(msg.as_slice()),
+                None => ()
+            }
+            if Some(n) == count { return }
+        }
+    }
+
+    // run one command; false when it asks to quit
+    pub fn exec(&mut self, cmd: Command, ui: &mut Frontend) -> bool {
+        match self.try_exec(cmd, ui) {
+            Ok(go_on) => go_on,
+            Err(e) => { self.say(e.as_slice()); true }
+        }
+    }
+
+    // run one command, returning its error instead of saying it
+    pub fn try_exec(&mut self, cmd: Command, ui: &mut Frontend) -> Result<bool, ~str> {
+        match cmd {
+            Step(n) => self.go(Some(n), false, ui),
+            Continue => self.go(None, false, ui),
+            Animate => self.go(None, true, ui),
+            Break(loc) => {
+                let addr = try!(self.resolve(&loc)) & 0xfffe;
+                if !self.breakpoints.contains(&addr) { self.breakpoints.push(addr) }
+                let msg = format!("Breakpoint added: {:04x} {}", addr, self.symbols.label(addr));
+                self.say(msg.as_slice())
+            },
+            Delete(None) => {
+                self.breakpoints = ~[];
+                self.watches = ~[];
+                self.say("Deleted all break- and watchpoints")
+            },
+            Delete(Some(loc)) => {
+                let addr = try!(self.resolve(&loc));
+                let before = self.breakpoints.len() + self.watches.len();
+                self.breakpoints.retain(|&b| b != addr & 0xfffe);
+                self.watches.retain(|&(w, _)| w != addr);
+                if self.breakpoints.len() + self.watches.len() == before {
+                    return Err(format!("Nothing set at {:04x}", addr))
+                }
+                self.say(format!("Deleted {:04x}", addr).as_slice())
+            },
+            Watch(loc) => {
+                let addr = try!(self.resolve(&loc));
+                let now = self.cpu.ram.loadw(addr);
+                self.watches.retain(|&(w, _)| w != addr);
+                self.watches.push((addr, now));
+                self.say(format!("Watching {:04x} (now {:04x})", addr, now).as_slice())
+            },
+            Examine(count, format, unit, loc) => {
+                let addr = try!(self.resolve(&loc));
+                for line in self.examine(count, format, unit, addr).iter() { self.say(line.as_slice()) }
+            },
+            Set(RegTarget(r), values) => {
+                let v = try!(self.resolve(&values[0]));
+                try!(self.set_reg(r, v));
+                self.say(format!("r{} = {:04x}", r, v).as_slice())
+            },
+            Set(FlagTarget(mask), values) => {
+                let on = try!(self.resolve(&values[0])) != 0;
+                let sr = self.cpu.regs.arr[2];
+                try!(self.set_reg(2, if on { sr | mask } else { sr & !mask }));
+                let msg = format!("sr = {:04x} ({})", self.cpu.regs.arr[2], command::flags(self.cpu.regs.arr[2]));
+                self.say(msg.as_slice())
+            },
+            Set(MemTarget(loc, unit), values) => {
+                let addr = try!(self.resolve(&loc));
+                let mut bytes = ~[];
+                for v in values.iter() {
+                    let n = try!(self.resolve(v));
+                    if unit == 1 && n > 0xff { return Err(format!("{:x} is not a byte", n)) }
+                    bytes.push((n & 0xff) as u8);
+                    if unit == 2 { bytes.push((n >> 8) as u8) }
+                }
+                if try!(self.write(addr, bytes)) { ui.reload(self) }
+                self.say(format!("Wrote {} bytes at {:04x}", bytes.len(), addr).as_slice())
+            },
+            Write(loc, bytes) => {
+                let addr = try!(self.resolve(&loc));
+                if try!(self.write(addr, bytes)) { ui.reload(self) }
+                self.say(format!("Wrote {} bytes at {:04x}", bytes.len(), addr).as_slice())
+            },
+            Fill(loc, len, byte) => {
+                let addr = try!(self.resolve(&loc));
+                let len = try!(self.resolve(&len)) as uint;
+                let b = try!(self.resolve(&byte));
+                if b > 0xff { return Err(format!("{:x} is not a byte", b)) }
+                if try!(self.write(addr, ::std::vec::from_elem(len, b as u8))) { ui.reload(self) }
+                self.say(format!("Filled {:04x}-{:04x} with {:02x}", addr, addr as uint + len, b).as_slice())
+            },
+            Edit(loc) => {
+                let addr = try!(self.resolve(&loc));
+                ui.edit(self, addr)
+            },
+            EditRegs => ui.edit_regs(self),
+            Disas(loc) => {
+                let addr = match loc {
+                    Some(l) => try!(self.resolve(&l)),
+                    None => self.cpu.inst.memloc
+                };
+                for line in self.disas(addr).iter() { self.say(line.as_slice()) }
+            },
+            Info(what) => for line in try!(self.info(what.as_slice())).iter() { self.say(line.as_slice()) },
+            Backtrace => {
+                for line in stack::describe(&self.cpu, &self.symbols).iter() { self.say(line.as_slice()) }
+                ui.pane(self, gui::StackPane)
+            },
+            Xrefs(loc) => {
+                let addr = try!(self.resolve(&loc));
+                let text = self.symbols.describe_xrefs(addr);
+                self.cpu.buf.push_str(text.as_slice())
+            },
+            Patch(src) => {
+                try!(self.patch(src.as_slice()));
+                ui.reload(self)
+            },
+            Input(line) => {
+                self.inputs.push(line);
+                if self.cpu.pending_input().is_some() {
+                    self.feed(ui);
+                } else {
+                    let msg = format!("Queued for the next getsn ({} waiting)", self.inputs.len());
+                    self.say(msg.as_slice())
+                }
+            },
+            Source(path) => if !try!(script::source(self, path.as_slice(), ui)) { return Ok(false) },
+            Run => {
+                self.reset();
+                ui.reload(self);
+                self.go(None, false, ui)
+            },
+            Reset => {
+                self.reset();
+                ui.reload(self);
+                self.say("Reset")
+            },
+            Pages => ui.pane(self, gui::PagePane),
+            Heap(loc) => {
+                match loc {
+                    Some(l) => {
+                        let base = try!(self.resolve(&l));
+                        if self.heap.as_ref().map_or(true, |h| h.base != base) { self.heap = Some(heap::HeapView::new(base)) }
+                    },
+                    None if self.heap.is_none() => return Err(~"heap needs a base the first time"),
+                    None => ()
+                }
+                ui.pane(self, gui::HeapPane)
+            },
+            Redraw => (),
+            Help(name) => for line in command::help(name).iter() { self.say(line.as_slice()) },
+            Quit => return Ok(false)
+        }
+        ui.show(self);
+        Ok(true)
+    }
+
+    fn examine(&self, count: uint, format: char, unit: uint, addr: u16) -> ~[~str] {
+        let ram = &self.cpu.ram;
+        match format {
+            's' => {
+                let mut out = ~[];
+                let mut a = addr;
+                for _ in range(0, count) {
+                    let mut s = ~"";
+                    while ram.loadb(a) != 0 && s.len() < 256 {
+                        s.push_char(ram.loadb(a) as char);
+                        a += 1;
+                    }
+                    out.push(format!("{:04x}: \"{}\"", a - s.len() as u16, s.escape_default()));
+                    a += 1;
+                }
+                out
+            },
+            'i' => {
+                let rows : ~[~str] = self.listing.iter().filter(|&&(a, _)| a >= addr).take(count)
+                    .map(|&(a, ref text)| format!("{:04x}: {} {}", a, *text, self.symbols.label(a))).collect();
+                if rows.len() == 0 { ~[format!("Nothing disassembled at {:04x}", addr)] } else { rows }
+            },
+            _ => {
+                let per = 16 / unit;
+                let mut out = ~[];
+                let mut line = ~"";
+                for ix in range(0, count) {
+                    let a = addr + (ix * unit) as u16;
+                    if ix % per == 0 {
+                        if ix > 0 { out.push(line.clone()) }
+                        line = format!("{:04x}:", a);
+                    }
+                    let v = if unit == 1 { ram.loadb(a) as u16 } else { ram.loadw(a) };
+                    line.push_str(match (format, unit) {
+                        ('d', _) => format!(" {}", v),
+                        ('c', 1) => format!(" {}", show_char(v as u8)),
+                        ('c', _) => format!(" {}{}", show_char(v as u8), show_char((v >> 8) as u8)),
+                        (_, 1) => format!(" {:02x}", v),
+                        _ => format!(" {:04x}", v)
+                    });
+                }
+                out.push(line);
+                out
+            }
+        }
+    }
+
+    // the listing of the function around addr, or the next 16 rows outside any
+    fn disas(&self, addr: u16) -> ~[~str] {
+        let (start, end) = match self.symbols.function(addr) {
+            Some(f) => (f.start, f.end),
+            None => (addr, 0xffff)
+        };
+        let mut out = ~[];
+        for &(a, ref text) in self.listing.iter().filter(|&&(a, _)| a >= start && a < end) {
+            if end == 0xffff && out.len() == 16 { break }
+            let mark = if a == self.cpu.inst.memloc { "=>" } else { "  " };
+            let note = self.symbols.comments.find(&a).map_or(~"", |c| c.clone());
+            out.push(format!("{} {:04x}: {} {}", mark, a, *text, note));
+        }
+        match self.symbols.starting_at(start) {
+            Some(f) => out.unshift(format!("{}:", f.name)),
+            None => ()
+        }
+        out
+    }
+
+    fn info(&self, what: &str) -> Result<~[~str], ~str> {
+        let cpu = &self.cpu;
+        Ok(match what {
+            "regs" => {
+                let mut out = ~[format!("pc {:04x} {}  flags {}", cpu.inst.memloc, self.symbols.label(cpu.inst.memloc),
+                                        command::flags(cpu.regs.arr[2]))];
+                for row in range(0u8, 4) {
+                    let cells : ~[~str] = range(row * 4, row * 4 + 4).map(|r| {
+                        let (name, v) = match r {
+                            0 => (~"pc", cpu.inst.memloc),
+                            1 => (~"sp", cpu.regs.arr[1]),
+                            2 => (~"sr", cpu.regs.arr[2]),
+                            3 => (~"cg", cpu.regs.arr[3]),
+                            _ => (format!("r{}", r), cpu.regs.arr[r])
+                        };
+                        format!("{:3s} {:04x}", name, v)
+                    }).collect();
+                    out.push(cells.connect("  "));
+                }
+                out
+            },
+            "break" if self.breakpoints.len() == 0 => ~[~"No breakpoints"],
+            "break" => self.breakpoints.iter().map(|&b| format!("{:04x} {}", b, self.symbols.label(b))).collect(),
+            "watch" if self.watches.len() == 0 => ~[~"No watchpoints"],
+            "watch" => self.watches.iter().map(|&(a, v)| format!("{:04x} = {:04x}", a, v)).collect(),
+            "inputs" => {
+                let mut out : ~[~str] = cpu.inputs.iter().enumerate().map(|(ix, &(buf, ref bytes))| {
+                    format!("{}: {} bytes at {:04x}: {}", ix + 1, bytes.len(), buf, ::symex::show_input(bytes.as_slice()))
+                }).collect();
+                for line in self.inputs.iter() { out.push(format!("queued: {}", *line)) }
+                if out.len() == 0 { out.push(~"No input yet") }
+                out
+            },
+            "frames" => stack::describe(cpu, &self.symbols),
+            "heap" => match self.heap {
+                Some(ref h) => {
+                    let mut view = h.clone();
+                    view.update(cpu);
+                    view.entries(cpu).iter().map(|e| {
+                        let mut notes = e.problems.clone();
+                        notes.push_all(e.changes);
+                        if notes.len() > 0 { format!("{}  {}", e.describe(), notes.connect(", ")) } else { e.describe() }
+                    }).collect()
+                },
+                None => return Err(~"No heap base, set one with heap BASE")
+            },
+            _ => return Err(format!("info {}?", what))
+        })
+    }
+
+    // [!]ADDR instructions: assemble at ADDR and write the bytes into memory
+    fn patch(&mut self, s: &str) -> Result<(), ~str> {
+        let s = s.trim();
+        let (force, s) = if s.starts_with("!") { (true, s.slice_from(1)) } else { (false, s) };
+        let (addr, src) = match s.find(|c: char| c.is_whitespace()) {
+            Some(ix) => (s.slice_to(ix), s.slice_from(ix).trim()),
+            None => (s, "")
+        };
+        let addr = try!(self.resolve(&try!(command::parse_loc(addr))));
+        let bytes = try!(asm::assemble(src, addr));
+        match self.cpu.ram.patch(addr as u32, bytes, force) {
+            Ok(()) => {
+                self.cpu.refetch();
+                self.listing = cpu::disassemble(self.cpu.ram.bytes(), self.cpu.cpux);
+                self.symbols = Analysis::run(self.cpu.ram.bytes(), self.cpu.cpux);
+                self.say(format!("Patched {} bytes at {:04x}", bytes.len(), addr));
+                Ok(())
+            },
+            Err(f) => Err(format!("Patch refused: {} (use !{:04x} to ignore DEP)", f, addr))
+        }
+    }
+}
+
+fn show_char(b: u8) -> ~str {
+    ::std::str::from_char(b as char).escape_default()
+}
+
+// prints the console as it grows; input only comes from the queue
+pub struct Headless {
+    shown: uint                     //bytes of the console already printed
+}
+
+impl Frontend for Headless {
+    fn show(&mut self, s: &Session) {
+        if s.cpu.buf.len() > self.shown {
+            print!("{}", s.cpu.buf.slice_from(self.shown));
+            self.shown = s.cpu.buf.len();
+        }
+    }
+    fn input(&mut self, _: &Session) -> Option<~str> { None }
+    fn reload(&mut self, _: &Session) {}
+    fn pane(&mut self, _: &Session, _: gui::Pane) {}
+    fn edit(&mut self, s: &mut Session, _: u16) { s.say("edit needs the TUI, use set or fill") }
+    fn edit_regs(&mut self, s: &mut Session) { s.say("edit needs the TUI, use set") }
+}
+
+// uctf --ex CMD [--ex CMD ...] IMAGE: run debugger commands without the TUI
+pub fn headless(session: &mut Session, cmds: &[~str]) {
+    let mut ui = Headless { shown: 0 };
+    for line in cmds.iter() {
+        println!("> {}", *line);
+        match command::parse(line.as_slice()) {
+            Ok(cmd) => if !session.exec(cmd, &mut ui as &mut Frontend) { break },
+            Err(e) => session.say(e.as_slice())
+        }
+        ui.show(session);
+    }
+}
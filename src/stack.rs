@@ -0,0 +1,83 @@
+// Backtraces
+//
+// The CPU keeps a shadow call stack, pushing a frame on every CALL and popping it on
+// the matching RET. Each frame remembers the return address it pushed, so one that no
+// longer matches the stack has been overwritten. With no frames (e.g. after patching
+// the pc) the stack is scanned for words that follow a CALL instead.
+
+use analysis::Analysis;
+use cpu::Cpu;
+use mem::{Mem, MemUtil};
+
+static SCANWORDS : u16 = 64;
+
+#[deriving(Clone)]
+pub struct Frame {
+    call: u16,          //address of the CALL
+    target: u16,        //function called
+    ret: u16,           //return address pushed
+    slot: u16,          //where it was pushed
+    gate: bool          //call into the microcorruption INT gate
+}
+
+pub struct BtEntry {
+    pc: u16,                    //return address on the stack, the pc for the innermost frame
+    sp: u16,
+    expected: Option<u16>,      //return address pushed by the CALL, if it was overwritten
+    gate: bool,
+    scanned: bool               //found by the stack scan
+}
+
+pub fn backtrace(cpu: &Cpu) -> ~[BtEntry] {
+    let mut out = ~[BtEntry { pc: cpu.inst.memloc, sp: cpu.regs.arr[1], expected: None, gate: false, scanned: false }];
+    if cpu.frames.len() == 0 {
+        out.push_all_move(scan(cpu));
+        return out
+    }
+    for f in cpu.frames.rev_iter() {
+        let actual = cpu.ram.loadw(f.slot);
+        let expected = if actual != f.ret { Some(f.ret) } else { None };
+        out.push(BtEntry { pc: actual, sp: f.slot, expected: expected, gate: f.gate, scanned: false });
+    }
+    out
+}
+
+// return addresses among the words above sp
+fn scan(cpu: &Cpu) -> ~[BtEntry] {
+    let mut out = ~[];
+    let sp = cpu.regs.arr[1] & 0xfffe;
+    for ix in range(0, SCANWORDS) {
+        let slot = sp as uint + 2 * ix as uint;
+        if slot > 0xfffe { break }
+        let w = cpu.ram.loadw(slot as u16);
+        if after_call(cpu, w) {
+            out.push(BtEntry { pc: w, sp: slot as u16, expected: None, gate: false, scanned: true });
+        }
+    }
+    out
+}
+
+// whether the instruction ending at addr is a CALL
+fn after_call(cpu: &Cpu, addr: u16) -> bool {
+    if addr % 2 != 0 || addr < 4 { return false }
+    let c4 = cpu.ram.loadw(addr - 4);
+    let c2 = cpu.ram.loadw(addr - 2);
+    c4 == 0x12b0 || c4 & 0xfff0 == 0x1290 ||            //CALL #imm, CALL x(rN)/&x
+        (c2 & 0xffc0 == 0x1280 && c2 & 0x30 != 0x10 && c2 != 0x12b0)   //CALL rN, @rN, @rN+
+}
+
+// one line per frame, innermost first
+pub fn describe(cpu: &Cpu, symbols: &Analysis) -> ~[~str] {
+    let mut out = ~[];
+    for (ix, e) in backtrace(cpu).iter().enumerate() {
+        let mut line = format!("\\#{} {:04x} {} sp {:04x}", ix, e.pc, symbols.label(e.pc), e.sp);
+        if e.gate { line.push_str(" (INT gate)") }
+        if e.scanned { line.push_str(" (scan)") }
+        match e.expected {
+            Some(x) => line.push_str(format!(" !! expected {:04x}, stack smashed?", x)),
+            None => ()
+        }
+        out.push(line);
+    }
+    out
+}
@@ -0,0 +1,660 @@
+// Symbolic execution: find input that unlocks the door
+//
+// Bytes read by getsn are symbolic. Instructions build 16-bit expressions over them,
+// following the emulator's own arithmetic and flag rules, and a conditional jump whose
+// flags depend on input forks the path, adding the condition (or its negation) to each
+// side's constraints. Paths are explored breadth first until one reaches the 0xff00
+// unlock gate, or a chosen address, with constraints that still hold. A small
+// backtracking solver then picks the input bytes, trying the constants the program
+// compares against first.
+//
+// Not modelled: MSP430X, DEP and the memory map (all memory is writable), DADD and
+// RETI. A symbolic address is pinned to one value the constraints allow, and the
+// 0xa000 random gate always returns 0.
+
+use collections::{HashSet, HashMap, RingBuf, Deque};
+use cpu::{Cpu, Instruction, NoArg, OneArg, TwoArg};
+use cpu::{AddressingMode, Direct, Indexed, Indirect, IndirectInc, Absolute, Const};
+use device::Device;
+use runner;
+use std::os;
+use std::rc::Rc;
+
+static MAXPATHS : uint = 20000;         //paths taken off the queue before giving up
+static MAXSTEPS : uint = 2000000;       //instructions over all paths
+static SOLVELIMIT : uint = 1000000;     //assignments tried per solver call
+
+// flags as they sit in the status register: C, Z, N, V
+static FLAGBITS : [u16, ..4] = [0, 1, 2, 8];
+static FLAGMASK : u16 = 0x107;
+static C : uint = 0;
+static Z : uint = 1;
+static N : uint = 2;
+static V : uint = 3;
+
+pub enum Op { Add, And, Or, Xor, Shl, Shr, Eq, Ult }
+
+pub enum Expr {
+    Num(u16),
+    Byte(uint),             //input byte n, zero extended
+    Bin(Op, Val, Val),      //Eq and Ult give 0 or 1
+    Inv(Val)                //bitwise not
+}
+
+pub type Val = Rc<Expr>;
+
+fn num(n: u16) -> Val { Rc::new(Num(n)) }
+
+fn const_of(v: &Val) -> Option<u16> {
+    match **v {
+        Num(n) => Some(n),
+        _ => None
+    }
+}
+
+fn is_byte(v: &Val) -> bool {
+    match **v {
+        Byte(_) => true,
+        _ => false
+    }
+}
+
+fn same(a: &Val, b: &Val) -> bool {
+    (&**a as *Expr) == (&**b as *Expr)
+}
+
+fn apply(op: Op, a: u16, b: u16) -> u16 {
+    match op {
+        Add => a + b,
+        And => a & b,
+        Or => a | b,
+        Xor => a ^ b,
+        Shl => if b > 15 { 0 } else { a << b },
+        Shr => if b > 15 { 0 } else { a >> b },
+        Eq => if a == b { 1 } else { 0 },
+        Ult => if a < b { 1 } else { 0 }
+    }
+}
+
+// build a Bin, folding constants so concrete code stays concrete
+fn bin(op: Op, a: Val, b: Val) -> Val {
+    match (const_of(&a), const_of(&b)) {
+        (Some(x), Some(y)) => return num(apply(op, x, y)),
+        (_, Some(0)) => match op {
+            Add | Or | Xor | Shl | Shr => return a,
+            And => return num(0),
+            _ => ()
+        },
+        (Some(0), _) => match op {
+            Add | Or | Xor => return b,
+            And | Shl | Shr => return num(0),
+            _ => ()
+        },
+        (_, Some(0xff)) if is_byte(&a) => match op {
+            And => return a,
+            _ => ()
+        },
+        _ => ()
+    }
+    Rc::new(Bin(op, a, b))
+}
+
+fn inv(a: Val) -> Val {
+    let inner = match *a {
+        Num(n) => return num(!n),
+        Inv(ref x) => Some(x.clone()),
+        _ => None
+    };
+    inner.unwrap_or(Rc::new(Inv(a)))
+}
+
+fn neg(a: Val) -> Val { bin(Add, inv(a), num(1)) }
+fn lnot(a: Val) -> Val { bin(Eq, a, num(0)) }
+fn bit(v: Val, n: u16) -> Val { bin(And, bin(Shr, v, num(n)), num(1)) }
+
+// a word from its two bytes, undoing the split made when it was stored
+fn join(lo: Val, hi: Val) -> Val {
+    let whole = match (&*lo, &*hi) {
+        (&Bin(And, ref x, ref m), &Bin(Shr, ref y, ref s))
+            if same(x, y) && const_of(m) == Some(0xff) && const_of(s) == Some(8) => Some(x.clone()),
+        _ => None
+    };
+    match whole {
+        Some(x) => x,
+        None => bin(Or, lo, bin(Shl, hi, num(8)))
+    }
+}
+
+// value of e with the input bytes in model, None if it needs one that is unassigned
+fn eval(e: &Val, model: &[Option<u8>]) -> Option<u16> {
+    match **e {
+        Num(n) => Some(n),
+        Byte(ix) => model[ix].map(|b| b as u16),
+        Inv(ref a) => eval(a, model).map(|x| !x),
+        Bin(op, ref a, ref b) => match (eval(a, model), eval(b, model)) {
+            (Some(x), Some(y)) => Some(apply(op, x, y)),
+            _ => None
+        }
+    }
+}
+
+fn vars(e: &Val, out: &mut ~[uint]) {
+    match **e {
+        Num(_) => (),
+        Byte(ix) => out.push(ix),
+        Inv(ref a) => vars(a, out),
+        Bin(_, ref a, ref b) => { vars(a, out); vars(b, out) }
+    }
+}
+
+// constants an input byte is compared with, the likeliest values for it
+fn hints(e: &Val, out: &mut ~[~[u8]]) {
+    match **e {
+        Bin(op, ref a, ref b) => {
+            match (op, &**a, &**b) {
+                (Eq, &Byte(ix), &Num(n)) | (Eq, &Num(n), &Byte(ix)) if n < 0x100 => out[ix].push(n as u8),
+                _ => ()
+            }
+            hints(a, out);
+            hints(b, out)
+        },
+        Inv(ref a) => hints(a, out),
+        _ => ()
+    }
+}
+
+struct Search<'a> {
+    constraints: &'a [Val],
+    order: ~[uint],             //variables in the order they are assigned
+    checks: ~[~[uint]],         //constraints decided by each assignment
+    cands: ~[~[u8]],            //values to try, by variable
+    model: ~[Option<u8>],
+    budget: uint
+}
+
+impl<'a> Search<'a> {
+    fn assign(&mut self, depth: uint) -> bool {
+        if depth == self.order.len() { return true }
+        let v = self.order[depth];
+        for ix in range(0, self.cands[v].len()) {
+            if self.budget == 0 { break }
+            self.budget -= 1;
+            self.model[v] = Some(self.cands[v][ix]);
+            let ok = self.checks[depth].iter().all(|&c| eval(&self.constraints[c], self.model.as_slice()) != Some(0));
+            if ok && self.assign(depth + 1) { return true }
+        }
+        self.model[v] = None;
+        false
+    }
+}
+
+// input bytes satisfying every constraint, None if there are none or the search gave up.
+// Bytes no constraint mentions are 'A'.
+pub fn solve(constraints: &[Val], nvars: uint) -> Option<~[u8]> {
+    let mut pos : ~[Option<uint>] = range(0, nvars).map(|_| None).collect();
+    let mut order = ~[];
+    let mut checks : ~[~[uint]] = ~[];
+    let mut likely : ~[~[u8]] = range(0, nvars).map(|_| ~[]).collect();
+    for (ci, c) in constraints.iter().enumerate() {
+        let mut vs = ~[];
+        vars(c, &mut vs);
+        if vs.len() == 0 {
+            if eval(c, &[]) == Some(0) { return None }
+            continue
+        }
+        let mut last = 0;
+        for &v in vs.iter() {
+            let p = match pos[v] {
+                Some(p) => p,
+                None => { pos[v] = Some(order.len()); order.push(v); checks.push(~[]); order.len() - 1 }
+            };
+            if p > last { last = p }
+        }
+        checks[last].push(ci);
+        hints(c, &mut likely);
+    }
+
+    let mut cands = ~[];
+    for hint in likely.move_iter() {
+        let mut seen = [false, ..256];
+        let mut vals = ~[];
+        let rest = range(0x41u, 0x7f).chain(range(0x20u, 0x41)).chain(range(0u, 0x20)).chain(range(0x7fu, 0x100));
+        for b in hint.move_iter().map(|b| b as uint).chain(rest) {
+            if !seen[b] { seen[b] = true; vals.push(b as u8) }
+        }
+        cands.push(vals);
+    }
+    let mut s = Search { constraints: constraints, order: order, checks: checks, cands: cands,
+                         model: range(0, nvars).map(|_| None).collect(), budget: SOLVELIMIT };
+    if !s.assign(0) { return None }
+    Some(s.model.iter().map(|b| b.unwrap_or(0x41)).collect())
+}
+
+#[deriving(Clone)]
+struct State {
+    regs: ~[Val],               //r2 holds the status bits other than the flags
+    flags: ~[Val],              //C, Z, N, V as 0 or 1
+    mem: HashMap<u16, Val>,     //bytes written, over the image
+    path: ~[Val],               //constraints, each must be nonzero
+    inputs: ~[(uint, uint)],    //first variable and length of each getsn
+    nvars: uint
+}
+
+impl State {
+    fn reg(&self, r: u8) -> Val {
+        if r != 2 { return self.regs[r as uint].clone() }
+        let mut sr = bin(And, self.regs[2].clone(), num(!FLAGMASK));
+        for (ix, &b) in FLAGBITS.iter().enumerate() {
+            sr = bin(Or, sr, bin(Shl, self.flags[ix].clone(), num(b)))
+        }
+        sr
+    }
+
+    fn set_reg(&mut self, r: u8, v: Val) {
+        if r == 2 {
+            for (ix, &b) in FLAGBITS.iter().enumerate() { self.flags[ix] = bit(v.clone(), b) }
+        }
+        self.regs[r as uint] = v
+    }
+
+    fn setzn(&mut self, v: Val) {
+        self.flags[Z] = lnot(v.clone());
+        self.flags[N] = bit(v, 15);
+    }
+
+    fn feasible(&self) -> bool {
+        solve(self.path, self.nvars).is_some()
+    }
+
+    // a concrete value for v, pinning it to one the constraints allow
+    fn concrete(&mut self, v: Val) -> Result<u16, ~str> {
+        match const_of(&v) {
+            Some(n) => return Ok(n),
+            None => ()
+        }
+        let model : ~[Option<u8>] = match solve(self.path, self.nvars) {
+            Some(m) => m.move_iter().map(|b| Some(b)).collect(),
+            None => return Err(~"no input satisfies the path")
+        };
+        let n = eval(&v, model).unwrap();
+        self.path.push(bin(Eq, v, num(n)));
+        Ok(n)
+    }
+}
+
+enum Next {
+    Go,
+    Branch(Val, u16),       //jump to the address if the condition holds
+    Unlock(Val),            //the door opens if the condition holds
+    Stop(~str)              //the path ends here
+}
+
+struct Engine {
+    image: ~[u8],               //memory at reset
+    decoder: Cpu,
+    callgates: bool,
+    hsm_password: Option<~[u8]>
+}
+
+impl Engine {
+    fn loadb(&self, st: &State, addr: u16) -> Val {
+        match st.mem.find(&addr) {
+            Some(v) => v.clone(),
+            None => num(self.image[addr as uint] as u16)
+        }
+    }
+
+    fn load(&self, st: &State, addr: u16, bw: bool) -> Val {
+        let lo = self.loadb(st, addr);
+        if bw { lo } else { join(lo, self.loadb(st, addr + 1)) }
+    }
+
+    fn store(&self, st: &mut State, addr: u16, val: Val, bw: bool) {
+        st.mem.insert(addr, bin(And, val.clone(), num(0xff)));
+        if !bw { st.mem.insert(addr + 1, bin(Shr, val, num(8))); }
+    }
+
+    // as Cpu::resolve
+    fn resolve(&self, st: &mut State, reg: u8, mode: AddressingMode, bw: bool) -> Result<Val, ~str> {
+        let regval = st.reg(reg);
+        let val = match mode {
+            Direct => regval,
+            Indirect => { let a = try!(st.concrete(regval)); self.load(st, a, bw) },
+            IndirectInc => {
+                let a = try!(st.concrete(regval));
+                st.set_reg(reg, num(a + 2));
+                self.load(st, a, bw)
+            },
+            Indexed(offset) => { let a = try!(st.concrete(bin(Add, regval, num(offset)))); self.load(st, a, bw) },
+            Absolute(address) => self.load(st, address, bw),
+            Const(n) => num(n)
+        };
+        Ok(if bw { bin(And, val, num(0xff)) } else { val })
+    }
+
+    // as Cpu::_store
+    fn put(&self, st: &mut State, reg: u8, mode: AddressingMode, bw: bool, val: Val) -> Result<(), ~str> {
+        let regval = st.reg(reg);
+        match mode {
+            Direct => st.set_reg(reg, val),
+            Indirect => { let a = try!(st.concrete(regval)); self.store(st, a, val, bw) },
+            IndirectInc => {
+                let a = try!(st.concrete(regval));
+                st.set_reg(reg, num(a + 1));
+                self.store(st, a, val, bw)
+            },
+            Indexed(offset) => { let a = try!(st.concrete(bin(Add, regval, num(offset)))); self.store(st, a, val, bw) },
+            Absolute(address) => self.store(st, address, val, bw),
+            Const(_) => ()
+        }
+        Ok(())
+    }
+
+    fn set_and_store(&self, st: &mut State, inst: &Instruction, val: Val) -> Result<(), ~str> {
+        st.setzn(val.clone());
+        self.put(st, inst.destreg, inst.destmode, inst.bw, val)
+    }
+
+    fn push(&self, st: &mut State, val: Val, bw: bool) -> Result<(), ~str> {
+        let sp = st.regs[1].clone();
+        let sp = try!(st.concrete(sp)) - 2;
+        st.regs[1] = num(sp);
+        self.store(st, sp, val, bw);
+        Ok(())
+    }
+
+    fn step(&mut self, st: &mut State) -> Result<Next, ~str> {
+        let pc = match const_of(&st.regs[0]) {
+            Some(pc) => pc,
+            None => return Ok(Stop(~"pc depends on input"))
+        };
+        if self.callgates && pc == 0x10 { return self.gate(st) }
+        let mut symbolic = None;
+        for ix in range(0u16, 6) {
+            let b = match const_of(&self.loadb(st, pc + ix)) {
+                Some(b) => b as u8,
+                None => { if symbolic.is_none() { symbolic = Some(ix) }; 0 }
+            };
+            self.decoder.ram.poke((pc + ix) as u32, b);
+        }
+        let len = match self.decoder.decode_at(pc) {
+            Some(len) => len,
+            None => return Ok(Stop(format!("invalid instruction at {:04x}", pc)))
+        };
+        match symbolic {
+            Some(ix) if ix < len => return Ok(Stop(format!("code at {:04x} depends on input", pc + ix))),
+            _ => ()
+        }
+        let inst = self.decoder.inst;
+        st.regs[0] = num(pc + len);
+        let next = try!(self.exec(st, &inst, pc + len));
+        match const_of(&st.regs[2]) {
+            Some(sr) if sr & 0x80 != 0 => Ok(Stop(~"CPU off")),
+            _ => Ok(next)
+        }
+    }
+
+    // as Cpu::exec_ for the MSP430 instruction set
+    fn exec(&self, st: &mut State, inst: &Instruction, next: u16) -> Result<Next, ~str> {
+        let bw = inst.bw;
+        match inst.optype {
+            NoArg => {
+                let cond = match inst.opcode {
+                    0b000 => lnot(st.flags[Z].clone()),
+                    0b001 => st.flags[Z].clone(),
+                    0b010 => lnot(st.flags[C].clone()),
+                    0b011 => st.flags[C].clone(),
+                    0b100 => st.flags[N].clone(),
+                    0b101 => bin(Eq, st.flags[N].clone(), st.flags[V].clone()),
+                    0b110 => lnot(bin(Eq, st.flags[N].clone(), st.flags[V].clone())),
+                    _ => num(1)
+                };
+                let target = next + inst.offset;
+                return Ok(match const_of(&cond) {
+                    Some(0) => Go,
+                    Some(_) => { st.regs[0] = num(target); Go },
+                    None => Branch(cond, target)
+                })
+            },
+            OneArg => {
+                let val = try!(self.resolve(st, inst.destreg, inst.destmode, bw));
+                match inst.opcode {
+                    0b000 => {
+                        let v = bin(Or, bin(Shr, val, num(1)), bin(Shl, st.flags[C].clone(), num(15)));
+                        try!(self.set_and_store(st, inst, v))
+                    },
+                    0b001 => {
+                        let v = bin(Or, bin(Shr, val.clone(), num(8)), bin(Shl, val, num(8)));
+                        try!(self.put(st, inst.destreg, inst.destmode, bw, v))
+                    },
+                    0b010 => try!(self.set_and_store(st, inst, bin(Shr, val, num(1)))),
+                    0b011 => {
+                        let ext = bin(And, neg(bit(val.clone(), 7)), num(0xff00));
+                        try!(self.put(st, inst.destreg, inst.destmode, bw, bin(Or, val, ext)))
+                    },
+                    0b100 => try!(self.push(st, val, bw)),
+                    0b101 => {
+                        let ret = st.regs[0].clone();
+                        try!(self.push(st, ret, false));
+                        st.regs[0] = val
+                    },
+                    _ => return Ok(Stop(format!("{} is not supported", inst.namer())))
+                }
+            },
+            TwoArg => {
+                let src = try!(self.resolve(st, inst.srcreg, inst.srcmode, bw));
+                let dst = try!(self.resolve(st, inst.destreg, inst.destmode, bw));
+                let carry = st.flags[C].clone();
+                match inst.opcode {
+                    0b0100 => try!(self.put(st, inst.destreg, inst.destmode, bw, src)),
+                    0b0101 | 0b0110 | 0b0111 | 0b1000 | 0b1001 => {
+                        let value = match inst.opcode {
+                            0b0101 => bin(Add, dst.clone(), src),
+                            0b0110 => bin(Add, bin(Add, dst.clone(), src), carry),
+                            0b0111 => bin(Add, bin(Add, dst.clone(), inv(src)), carry),
+                            _ => bin(Add, bin(Add, dst.clone(), inv(src)), num(1))
+                        };
+                        if inst.opcode == 0b1001 {
+                            st.setzn(value.clone())
+                        } else {
+                            try!(self.set_and_store(st, inst, value.clone()))
+                        }
+                        st.flags[C] = bin(Ult, value, dst)
+                    },
+                    0b1011 => st.setzn(bin(And, src, dst)),
+                    0b1100 => try!(self.put(st, inst.destreg, inst.destmode, bw, bin(And, dst, inv(src)))),
+                    0b1101 => try!(self.put(st, inst.destreg, inst.destmode, bw, bin(Or, dst, src))),
+                    0b1110 => try!(self.set_and_store(st, inst, bin(Xor, dst, src))),
+                    0b1111 => try!(self.set_and_store(st, inst, bin(And, dst, src))),
+                    _ => return Ok(Stop(format!("{} is not supported", inst.namer())))
+                }
+            },
+            _ => return Ok(Stop(format!("{} is not supported", inst.namer())))
+        }
+        Ok(Go)
+    }
+
+    fn arg(&self, st: &mut State, sp: u16, offset: u16) -> Result<u16, ~str> {
+        let v = self.load(st, sp + offset, false);
+        st.concrete(v)
+    }
+
+    // as Cpu::handle_interrupt, with getsn filling the buffer with fresh input bytes
+    fn gate(&self, st: &mut State) -> Result<Next, ~str> {
+        let sr = match const_of(&st.reg(2)) {
+            Some(sr) => sr,
+            None => return Ok(Stop(~"status register depends on input at the call gate"))
+        };
+        let sp = st.regs[1].clone();
+        let sp = try!(st.concrete(sp));
+        let mut next = Go;
+        match sr {
+            0x8000 | 0x9000 | 0x9100 => (),
+            0x8200 => {
+                let putloc = try!(self.arg(st, sp, 8));
+                let getn = try!(self.arg(st, sp, 10));
+                let first = st.nvars;
+                for i in range(0, getn) {
+                    st.mem.insert(putloc + i, Rc::new(Byte(first + i as uint)));
+                }
+                st.nvars += getn as uint;
+                st.inputs.push((first, getn as uint));
+            },
+            0xff00 => next = Unlock(num(1)),
+            0xfd00 => {
+                let pwloc = try!(self.arg(st, sp, 8));
+                let flagloc = try!(self.arg(st, sp, 10));
+                let ok = self.hsm_check(st, pwloc);
+                self.store(st, flagloc, ok, true)
+            },
+            0xfe00 => {
+                let pwloc = try!(self.arg(st, sp, 8));
+                next = Unlock(self.hsm_check(st, pwloc))
+            },
+            0xa000 => st.set_reg(15, num(0)),
+            w => return Ok(Stop(format!("interrupt {:04x} is not supported", w)))
+        }
+        //the MOV @sp+, pc that ends the gate
+        let ret = self.load(st, sp, false);
+        st.regs[1] = num(sp + 2);
+        st.regs[0] = ret;
+        Ok(next)
+    }
+
+    // 1 if the string at addr is the HSM password, as Cpu::hsm_check
+    fn hsm_check(&self, st: &State, addr: u16) -> Val {
+        match self.hsm_password {
+            None => num(0),
+            Some(ref pw) => {
+                let mut ok = num(1);
+                for (ix, &b) in pw.iter().enumerate() {
+                    ok = bin(And, ok, bin(Eq, self.loadb(st, addr + ix as u16), num(b as u16)))
+                }
+                bin(And, ok, lnot(self.loadb(st, addr + pw.len() as u16)))
+            }
+        }
+    }
+}
+
+pub struct Solution {
+    inputs: ~[~[u8]],       //one per getsn, up to the last byte that matters
+    paths: uint,
+    steps: uint
+}
+
+fn solution(st: &State, model: ~[u8], paths: uint, steps: uint) -> Solution {
+    let mut used = ~[];
+    for c in st.path.iter() { vars(c, &mut used) }
+    let used : HashSet<uint> = used.move_iter().collect();
+    let inputs = st.inputs.iter().map(|&(first, len)| {
+        let end = range(0, len).filter(|&i| used.contains(&(first + i))).max().map(|i| i + 1).unwrap_or(0);
+        //the runner takes an empty line as no input at all, so a prompt nothing checks gets a byte
+        if end == 0 { return ~['A' as u8] }
+        model.slice(first, first + end).to_owned()
+    }).collect();
+    Solution { inputs: inputs, paths: paths, steps: steps }
+}
+
+// explore paths from reset until one unlocks the door, or reaches target if given
+pub fn search(image: &[u8], dev: &Device, target: Option<u16>, hsm_password: Option<~[u8]>) -> Result<Solution, ~str> {
+    if dev.cpux { return Err(~"symbolic execution does not support MSP430X") }
+    let decoder = Cpu::init(image, dev);
+    let mut regs : ~[Val] = range(0, 16).map(|_| num(0)).collect();
+    regs[0] = num(decoder.inst.memloc);
+    let mut engine = Engine { image: decoder.ram.bytes(), decoder: decoder,
+                              callgates: dev.callgates, hsm_password: hsm_password };
+    let mut queue = RingBuf::new();
+    queue.push_back(State { regs: regs, flags: range(0, 4).map(|_| num(0)).collect(), mem: HashMap::new(),
+                            path: ~[], inputs: ~[], nvars: 0 });
+    let (mut paths, mut steps) = (0u, 0u);
+    let mut ended = ~"";
+    while paths < MAXPATHS {
+        let mut st = match queue.pop_front() {
+            Some(st) => st,
+            None => break
+        };
+        paths += 1;
+        loop {
+            steps += 1;
+            if steps > MAXSTEPS { return Err(format!("no solution after {} instructions", MAXSTEPS)) }
+            match target {
+                Some(t) if const_of(&st.regs[0]) == Some(t) => {
+                    match solve(st.path, st.nvars) {
+                        Some(model) => return Ok(solution(&st, model, paths, steps)),
+                        None => { ended = ~"reached the target but the solver gave up"; break }
+                    }
+                },
+                Some(t) if const_of(&st.regs[0]).is_none() => {
+                    //a return address or function pointer from input: try sending it to target
+                    st.path.push(bin(Eq, st.regs[0].clone(), num(t)));
+                    match solve(st.path, st.nvars) {
+                        Some(model) => return Ok(solution(&st, model, paths, steps)),
+                        None => { ended = ~"pc depends on input"; break }
+                    }
+                },
+                _ => ()
+            }
+            match engine.step(&mut st) {
+                Ok(Go) => (),
+                Ok(Branch(cond, dest)) => {
+                    let mut taken = st.clone();
+                    taken.regs[0] = num(dest);
+                    taken.path.push(cond.clone());
+                    st.path.push(lnot(cond));
+                    for s in (~[taken, st]).move_iter() {
+                        if s.feasible() { queue.push_back(s) }
+                    }
+                    break
+                },
+                Ok(Unlock(cond)) => {
+                    if target.is_none() {
+                        let mut won = st.clone();
+                        won.path.push(cond.clone());
+                        match solve(won.path, won.nvars) {
+                            Some(model) => return Ok(solution(&won, model, paths, steps)),
+                            None => ()
+                        }
+                    }
+                    st.path.push(lnot(cond));
+                    if !st.feasible() { ended = ~"door unlocked"; break }
+                },
+                Ok(Stop(why)) | Err(why) => { ended = why; break }
+            }
+        }
+    }
+    if paths >= MAXPATHS { return Err(format!("no solution within {} paths", MAXPATHS)) }
+    let goal = match target {
+        Some(t) => format!("reaches {:04x}", t),
+        None => ~"unlocks the door"
+    };
+    Err(format!("no path {} ({} explored, the last ended: {})", goal, paths, ended))
+}
+
+// printable text, or x-prefixed hex as the input prompt takes it
+pub fn show_input(bytes: &[u8]) -> ~str {
+    if bytes.len() > 0 && bytes.iter().all(|&b| b >= 0x20 && b < 0x7f) {
+        return bytes.iter().map(|&b| b as char).collect()
+    }
+    let hex : ~[~str] = bytes.iter().map(|b| format!("{:02x}", *b)).collect();
+    format!("x{}", hex.concat())
+}
+
+// uctf solve [--target ADDR] IMAGE
+pub fn run(image: &[u8], dev: &Device, target: Option<u16>, hsm_password: Option<~[u8]>) {
+    let sol = match search(image, dev, target, hsm_password.clone()) {
+        Ok(s) => s,
+        Err(e) => { println!("{}", e); os::set_exit_status(1); return }
+    };
+    println!("Solved after {} paths, {} instructions", sol.paths, sol.steps);
+    for (ix, input) in sol.inputs.iter().enumerate() {
+        println!("Input {}: {}", ix + 1, show_input(*input));
+    }
+    if target.is_some() { return }
+    //run it for real
+    let mut cpu = Cpu::init(image, dev);
+    cpu.hsm_password = hsm_password;
+    match runner::run(&mut cpu, sol.inputs.as_slice(), runner::STEPLIMIT) {
+        runner::Unlocked => println!("Checked: door unlocked"),
+        o => { println!("Check failed: {}", o.describe()); os::set_exit_status(1) }
+    }
+}
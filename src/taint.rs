@@ -0,0 +1,65 @@
+// Shadow taint over memory and registers
+//
+// Every byte delivered by getsn is labelled with its offset into the input. resolve()
+// records the labels of each operand it reads and _store() writes the labels of the
+// result: the source for MOV, source or destination byte for the ALU operations.
+// Addresses, flags and the 20-bit (.A) instructions are not tracked.
+
+use mem::Paged;
+
+pub type Label = Option<u16>;      //offset into the input the byte came from
+
+pub struct Taint {
+    mem: Paged<Label>,
+    regs: [[Label, ..2], ..16],     //low and high byte
+    operands: ~[[Label, ..2]]       //labels of the operands read by the current instruction
+}
+
+impl Clone for Taint {
+    fn clone(&self) -> Taint {
+        Taint { mem: self.mem.clone(), regs: self.regs, operands: self.operands.clone() }
+    }
+}
+
+impl Taint {
+    pub fn new() -> Taint {
+        Taint { mem: Paged::new(0x10000, None), regs: [[None, None], ..16], operands: ~[] }
+    }
+
+    pub fn load(&self, addr: u16, bw: bool) -> [Label, ..2] {
+        [self.mem.get(addr as uint), if bw { None } else { self.mem.get((addr + 1) as uint) }]
+    }
+
+    pub fn store(&mut self, addr: u16, labels: [Label, ..2], bw: bool) {
+        self.mem.set(addr as uint, labels[0]);
+        if !bw { self.mem.set((addr + 1) as uint, labels[1]) }
+    }
+
+    // labels of the nth operand read, untainted if there was none
+    pub fn operand(&self, n: uint) -> [Label, ..2] {
+        if n < self.operands.len() { self.operands[n] } else { [None, None] }
+    }
+
+    pub fn is_tainted(&self, addr: u16) -> bool {
+        self.mem.get(addr as uint).is_some()
+    }
+
+    pub fn reg_tainted(&self, reg: uint) -> bool {
+        self.regs[reg][0].is_some() || self.regs[reg][1].is_some()
+    }
+}
+
+// per byte, the first label present
+pub fn merge(a: [Label, ..2], b: [Label, ..2]) -> [Label, ..2] {
+    [a[0].or(b[0]), a[1].or(b[1])]
+}
+
+// "bytes 16-17", "byte 3", or None for untainted labels
+pub fn describe(labels: &[Label]) -> Option<~str> {
+    let mut offsets : ~[u16] = labels.iter().filter_map(|l| *l).collect();
+    if offsets.len() == 0 { return None }
+    offsets.sort();
+    offsets.dedup();
+    let (lo, hi) = (offsets[0], offsets[offsets.len() - 1]);
+    Some(if lo == hi { format!("byte {}", lo) } else { format!("bytes {}-{}", lo, hi) })
+}